@@ -0,0 +1,93 @@
+//! A tiny PILS-direct server, serving an in-memory image.
+//!
+//! Usage: `cargo run --example pils_server [port]`, then connect a client to
+//! `pils://localhost:port`.  Protected frames (`?crc`) are supported.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use zapf::proto::pils::{CMD_READ, CMD_WRITE, PROTECTED, STATUS_ERROR, STATUS_OK, crc16};
+
+const IMAGE_SIZE: usize = 0x10000;
+
+fn reply(stream: &mut TcpStream, seq: Option<[u8; 2]>, status: u8,
+         data: &[u8]) -> io::Result<()> {
+    let mut frame = vec![status];
+    if let Some(seq) = seq {
+        frame.extend_from_slice(&seq);
+    }
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    frame.extend_from_slice(data);
+    if seq.is_some() {
+        let crc = crc16(&frame);
+        frame.extend_from_slice(&crc.to_be_bytes());
+    }
+    stream.write_all(&frame)
+}
+
+fn handle(mut stream: TcpStream, image: Arc<Mutex<Vec<u8>>>) -> io::Result<()> {
+    loop {
+        let mut command = [0; 1];
+        stream.read_exact(&mut command)?;
+        let protected = command[0] & PROTECTED != 0;
+        let mut header = vec![0; if protected { 10 } else { 8 }];
+        stream.read_exact(&mut header)?;
+        let n = header.len();
+        let addr = u32::from_be_bytes([header[n-8], header[n-7], header[n-6], header[n-5]]);
+        let len = u32::from_be_bytes([header[n-4], header[n-3], header[n-2], header[n-1]]);
+        let (addr, len) = (addr as usize, len as usize);
+        let is_write = command[0] & !PROTECTED == CMD_WRITE;
+        let mut payload = vec![0; if is_write { len } else { 0 }];
+        stream.read_exact(&mut payload)?;
+
+        let seq = if protected {
+            let mut crc = [0; 2];
+            stream.read_exact(&mut crc)?;
+            let frame = [&command[..], &header, &payload].concat();
+            if crc16(&frame) != u16::from_be_bytes(crc) {
+                // can't trust anything in the frame, let the client resync
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "CRC mismatch"));
+            }
+            Some([header[0], header[1]])
+        } else {
+            None
+        };
+
+        let mut image = image.lock().unwrap();
+        if addr + len > image.len() {
+            reply(&mut stream, seq, STATUS_ERROR, b"address out of range")?;
+        } else if command[0] & !PROTECTED == CMD_READ {
+            reply(&mut stream, seq, STATUS_OK, &image[addr..addr + len])?;
+        } else if is_write {
+            image[addr..addr + len].copy_from_slice(&payload);
+            reply(&mut stream, seq, STATUS_OK, &[])?;
+        } else {
+            reply(&mut stream, seq, STATUS_ERROR, b"invalid command")?;
+        }
+    }
+}
+
+fn main() -> io::Result<()> {
+    simple_logger::init().unwrap();
+
+    let port = std::env::args().nth(1).unwrap_or_else(|| "5020".into());
+    let mut image = vec![0; IMAGE_SIZE];
+    image[..4].copy_from_slice(&2021.09f32.to_le_bytes());
+    let image = Arc::new(Mutex::new(image));
+
+    let listener = TcpListener::bind(("0.0.0.0", port.parse().expect("invalid port")))?;
+    log::info!("serving PILS-direct on port {}", port);
+    for client in listener.incoming() {
+        let client = client?;
+        let image = image.clone();
+        thread::spawn(move || {
+            let peer = client.peer_addr();
+            if let Err(e) = handle(client, image) {
+                log::info!("client {:?} disconnected: {}", peer, e);
+            }
+        });
+    }
+    Ok(())
+}
@@ -21,11 +21,14 @@
 //
 // *****************************************************************************
 
+use std::fmt;
+
 use zerocopy::AsBytes;
 
 use crate::{Error, Result};
 use crate::proto::Protocol;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Magic {
     M2015_02,
     M2021_09,
@@ -38,31 +41,105 @@ pub struct Io<P> {
 }
 
 impl<P: Protocol> Io<P> {
+    /// Connect and detect the magic, and the offset of the image.
+    ///
+    /// The indexer is not queried yet, for neither version of the magic:
+    /// its layout has to be taken from the PILS specification first.
     pub fn new(mut proto: P) -> Result<Self> {
         proto.connect()?;
         let cache = Cache {};
-        let magic = detect_magic(&mut proto)?;
+        let (magic, offset) = detect_magic(&mut proto)?;
+        proto.set_offset(offset);
         Ok(Self { magic, cache, proto })
     }
+
+    pub fn magic(&self) -> Magic {
+        self.magic
+    }
+}
+
+
+/// Result of reading the magic at one offset.
+#[derive(Debug)]
+pub enum MagicProbe {
+    /// The read failed with the given error.
+    ReadFailed(String),
+    /// The bytes don't form a magic number.
+    NoMagic([u8; 4]),
+    /// The bytes form a magic number, but of an unsupported version.
+    Unsupported(f32),
 }
 
+/// Report of all offsets probed during magic detection.
+#[derive(Debug, Default)]
+pub struct MagicReport {
+    pub probes: Vec<(usize, MagicProbe)>,
+}
 
-fn detect_magic<P: Protocol>(proto: &mut P) -> Result<Magic> {
-    let mut magic = 0f32;
-    for &offset in P::get_offsets() {
-        if proto.read_into(offset, magic.as_bytes_mut()).is_ok() {
-            if magic >= 2015. && magic <= 2045. {
-                if magic >= 2015.01 && magic <= 2015.03 {
-                    return Ok(Magic::M2015_02);
-                }
-                if magic >= 2021.08 && magic <= 2021.10 {
-                    return Ok(Magic::M2021_09);
-                }
-                return Err(Error::PLC(format!("Magic {} not supported", magic)));
+impl fmt::Display for MagicReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (offset, probe) in &self.probes {
+            write!(f, "\n  offset {:#x}: ", offset)?;
+            match probe {
+                MagicProbe::ReadFailed(e) => write!(f, "read failed: {}", e)?,
+                MagicProbe::NoMagic(bytes) => write!(f, "no magic in bytes {:02x?} (float {})",
+                                                     bytes, f32::from_ne_bytes(*bytes))?,
+                MagicProbe::Unsupported(magic) => write!(f, "magic {} is not supported", magic)?,
             }
         }
+        Ok(())
+    }
+}
+
+/// Find the magic, returning it and the offset where it was found.
+fn detect_magic<P: Protocol + ?Sized>(proto: &mut P) -> Result<(Magic, usize)> {
+    let mut report = MagicReport::default();
+    for &offset in proto.get_offsets() {
+        let mut magic = 0f32;
+        let probe = match proto.read_into(offset, magic.as_bytes_mut()) {
+            Err(e) => MagicProbe::ReadFailed(e.to_string()),
+            Ok(()) if magic >= 2015.01 && magic <= 2015.03 =>
+                return Ok((Magic::M2015_02, offset)),
+            Ok(()) if magic >= 2021.08 && magic <= 2021.10 =>
+                return Ok((Magic::M2021_09, offset)),
+            Ok(()) if magic >= 2015. && magic <= 2045. => MagicProbe::Unsupported(magic),
+            Ok(()) => MagicProbe::NoMagic(magic.to_ne_bytes()),
+        };
+        report.probes.push((offset, probe));
     }
-    Err(Error::PLC(format!("No supported magic or offset found")))
+    Err(Error::NoMagic(report))
 }
 
 struct Cache {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakePlc;
+
+    #[test]
+    fn new_with_magic() {
+        let io = Io::new(FakePlc::with_magic(16)).unwrap();
+        assert_eq!(io.magic(), Magic::M2021_09);
+        assert!(io.proto.is_connected());
+    }
+
+    #[test]
+    fn new_with_server_image() {
+        // the image served by the pils_server example
+        let mut plc = FakePlc::new(0x10000);
+        plc.set(0, &2021.09f32.to_le_bytes());
+        assert_eq!(Io::new(plc).unwrap().magic(), Magic::M2021_09);
+
+        let mut plc = FakePlc::new(0x10000);
+        plc.set(0, &2015.02f32.to_le_bytes());
+        assert_eq!(Io::new(plc).unwrap().magic(), Magic::M2015_02);
+    }
+
+    #[test]
+    fn new_without_magic() {
+        let mut plc = FakePlc::new(16);
+        plc.set(0, &2014.07f32.to_le_bytes());
+        assert!(matches!(Io::new(plc), Err(Error::NoMagic(_))));
+    }
+}
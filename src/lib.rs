@@ -23,9 +23,13 @@
 
 pub mod io;
 pub mod proto;
+pub mod status;
+pub mod testing;
 
 use thiserror::Error;
 
+use crate::proto::Protocol;
+
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -44,6 +48,27 @@ pub enum Error {
     #[error("Modbus error: {0}")]
     Modbus(#[from] modbus::Error),
 
+    // SLMP (Mitsubishi MC protocol) specific error
+    #[error("SLMP error: {0}")]
+    SLMP(String),
+
+    // FINS (Omron) specific error
+    #[error("FINS error: {0}")]
+    FINS(String),
+
+    // PILS-direct server reported an error or sent an invalid response
+    #[error("PILS-direct error: {0}")]
+    PILS(String),
+
+    // TLS setup or handshake error
+    #[cfg(feature = "tls")]
+    #[error("TLS error: {0}")]
+    TLS(#[from] native_tls::Error),
+
+    // HTTP gateway reported an error or sent an invalid response
+    #[error("HTTP error: {0}")]
+    HTTP(String),
+
     // Exception from Tango
     #[cfg(feature = "tango_client")]
     #[error("Tango error: {0}")]
@@ -53,15 +78,119 @@ pub enum Error {
     #[error("Tango error: {0}")]
     TangoProto(&'static str),
 
+    // recorded transaction log doesn't match the requests
+    #[error("replay error: {0}")]
+    Replay(String),
+
     // Zapf error with annotation
     #[error("during {1}: {0}")]
     Wrapped(#[source] Box<Error>, &'static str),
 
+    // two reads of the same data returned different results
+    #[error("reads at {0} returned different data")]
+    ReadMismatch(usize),
+
+    // request aborted using a CancelToken
+    #[error("request cancelled")]
+    Cancelled,
+    // circuit breaker is open, the request wasn't attempted
+    #[error("circuit breaker open for another {0:?}")]
+    CircuitOpen(std::time::Duration),
+
+    // empty request, or request extending past the end of the addressable image
+    #[error("invalid access of {1} bytes at {0}")]
+    InvalidLength(usize, usize),
+    // bit number outside of a byte
+    #[error("invalid bit number {0}, must be less than 8")]
+    InvalidBit(u8),
+    // data and mask of a masked write differ in length
+    #[error("mask of {1} bytes does not match data of {0} bytes")]
+    MaskLength(usize, usize),
+
+    // feature not supported by the protocol backend
+    #[error("not supported: {0}")]
+    Unsupported(&'static str),
+
     #[error("PLC error: {0}")]
     PLC(String),
 
+    // no supported magic number found at any offset
+    #[error("no supported magic or offset found:{0}")]
+    NoMagic(io::MagicReport),
+
     // #[error(transparent)]
     // Other(#[from] anyhow::Error),
 }
 
+impl Error {
+    /// Check if the error is caused by the connection (as opposed to an
+    /// error reported by the other side, or a usage error).
+    pub fn is_connection_error(&self) -> bool {
+        match self {
+            Error::IO(_) => true,
+            Error::ADS(ads::Error::Io(..)) => true,
+            Error::Modbus(modbus::Error::Io(_)) => true,
+            Error::Wrapped(inner, _) => inner.is_connection_error(),
+            _ => false,
+        }
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(feature = "tango_client")]
+const CONNECT_FMT: &str = "ads://, modbus://, tango://, http://, slmp://, fins://, \
+                           pils:// or replay:// address";
+#[cfg(not(feature = "tango_client"))]
+const CONNECT_FMT: &str = "ads://, modbus://, http://, slmp://, fins://, pils:// \
+                           or replay:// address";
+
+/// Create and connect the protocol backend selected by the scheme of the
+/// given address.  Appending `+ssh` to the scheme connects through an SSH
+/// tunnel, see `proto::tunnel`.
+pub fn connect(addr: &str) -> Result<Box<dyn Protocol>> {
+    let mut proto = create(addr)?;
+    #[cfg(feature = "tracing")]
+    {
+        proto = Box::new(proto::traced::Traced::new(proto, addr));
+    }
+    proto.connect()?;
+    Ok(proto)
+}
+
+/// Create a connection to a redundant PLC pair, which uses the standby
+/// address when the primary fails, see `proto::failover`.  `on_switch` is
+/// called after each switchover, with true if the standby is used from now on.
+pub fn connect_redundant(primary: &str, standby: &str,
+                         on_switch: impl FnMut(bool) + Send + 'static)
+                         -> Result<Box<dyn Protocol>> {
+    let mut proto: Box<dyn Protocol> = Box::new(
+        proto::failover::FailoverProto::new(create(primary)?, create(standby)?)
+            .on_switch(on_switch));
+    #[cfg(feature = "tracing")]
+    {
+        proto = Box::new(proto::traced::Traced::new(proto, primary));
+    }
+    proto.connect()?;
+    Ok(proto)
+}
+
+fn create(addr: &str) -> Result<Box<dyn Protocol>> {
+    let scheme = addr.split("://").next().unwrap_or("");
+    if scheme.ends_with("+ssh") {
+        return Ok(Box::new(proto::tunnel::SshTunnel::new(addr, create)?));
+    }
+    let proto: Box<dyn Protocol> = match scheme {
+        "ads" | "ads+tls" => Box::new(proto::ads::AdsProto::new(addr)?),
+        "modbus" | "modbus+tls" => Box::new(proto::modbus::ModbusProto::new(addr)?),
+        #[cfg(feature = "tango_client")]
+        "tango" => Box::new(proto::tango::TangoProto::new(addr)?),
+        "http" => Box::new(proto::http::HttpProto::new(addr)?),
+        "slmp" => Box::new(proto::slmp::SlmpProto::new(addr)?),
+        "fins" | "fins+udp" => Box::new(proto::fins::FinsProto::new(addr)?),
+        "pils" => Box::new(proto::pils::PilsProto::new(addr)?),
+        "replay" => Box::new(proto::replay::ReplayProto::new(addr)?),
+        _ => return Err(Error::InvalidAddress(CONNECT_FMT)),
+    };
+    Ok(proto)
+}
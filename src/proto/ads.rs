@@ -21,24 +21,107 @@
 //
 // *****************************************************************************
 
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use itertools::Itertools;
 use regex::Regex;
 use once_cell::sync::Lazy;
 
 use crate::{Error, Result};
-use crate::proto::{CONNECT_TIMEOUT, Protocol, READ_TIMEOUT, WRITE_TIMEOUT};
+use crate::proto::{Preflight, Protocol, Timeouts, check_bit, check_length, parse_query};
+use crate::proto::cancel::{Cancel, CancelToken};
+use crate::proto::socks::{Socks5Proxy, Socks5Relay};
 
 static ADS_ADDR_RE: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(r"ads://(.+?)/(\d+.\d+.\d+.\d+(.\d+.\d+)?):(\d+)$")
+    Regex::new(r"ads(\+tls)?://(.+?)/(\d+.\d+.\d+.\d+(.\d+.\d+)?):(\d+)(?:\?(.*))?$")
         .expect("invalid regex")
 });
-const ADS_ADDR_FMT: &str = "ads://host[:port]/amsnetid:amsport";
+const ADS_ADDR_FMT: &str = "ads[+tls]://host[:port]/amsnetid:amsport[?options]";
+
+/// Index group to query symbol information (address and size) by name.
+const SYM_INFOBYNAMEEX: u32 = 0xF009;
+/// Index group for bit access to the %M area, with the bit number as offset.
+const PLC_RW_MX: u32 = 0x4021;
+/// Index group for sum-up read requests.
+const SUMUP_READ: u32 = 0xF080;
+/// Index group for sum-up write requests.
+const SUMUP_WRITE: u32 = 0xF081;
+
+/// Size of single reads within a sum-up request.
+const SUMUP_CHUNK: usize = 1024;
+/// Maximum number of sub-requests in one sum-up request.
+const SUMUP_MAX: usize = 500;
+
+/// How to read data that is larger than `SUMUP_CHUNK`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReadStrategy {
+    Unknown,
+    Single,
+    SumUp,
+}
+
+/// Default TCP port for Secure ADS.
+pub const SECURE_ADS_PORT: u16 = 8016;
+
+/// Options for a Secure ADS connection, given as address query parameters
+/// (`ca=`, `cert=`, `key=` as file names, or `psk=identity:key`).
+#[derive(Debug, Default, Clone)]
+pub struct TlsOptions {
+    pub ca: Option<String>,
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    pub psk: Option<(String, String)>,
+}
+
+/// The memory area the PLC image is accessed in.
+#[derive(Debug, Clone, Copy)]
+struct Area {
+    group: u32,
+    offset: u32,
+    size: Option<usize>,
+}
+
+const M_AREA: Area = Area { group: ads::index::PLC_RW_M, offset: 0, size: None };
+
+/// Offset of the Windows FILETIME epoch (1601) from the Unix epoch, in 100 ns.
+const FILETIME_OFFSET: u64 = 116_444_736_000_000_000;
+
+/// A change of a subscribed range, as notified by the PLC.
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub addr: usize,
+    pub time: SystemTime,
+    pub data: Vec<u8>,
+}
+
+/// Where to deliver the notifications for one handle.
+struct Subscription {
+    addr: usize,
+    sender: mpsc::Sender<Change>,
+}
+
+type Subscriptions = Arc<Mutex<HashMap<ads::notif::Handle, Subscription>>>;
 
 pub struct AdsProto {
     host: String,
     port: u16,
     target: ads::AmsAddr,
+    tls: Option<TlsOptions>,
+    symbol: Option<String>,
+    area: Area,
+    socks: Option<Socks5Proxy>,
+    relay: Option<Socks5Relay>,
+    strategy: ReadStrategy,
     tried_route: bool,
+    // source address to use, kept from the first connection unless given
+    source: Option<ads::AmsAddr>,
+    timeouts: Timeouts,
+    cancel: Cancel,
+    subscriptions: Subscriptions,
+    dispatching: bool,
     client: Option<ads::Client>,
 }
 
@@ -47,25 +130,90 @@ impl AdsProto {
         let err0 = || Error::InvalidAddress(ADS_ADDR_FMT);
         let err1 = |_| Error::InvalidAddress(ADS_ADDR_FMT);
         let caps = ADS_ADDR_RE.captures(addr).ok_or_else(err0)?;
-        let host = &caps[1];
+        let secure = caps.get(1).is_some();
+        let host = &caps[2];
         let (host, port) = if host.contains(':') {
             let (h1, p1) = host.splitn(2, ':').collect_tuple().expect("split");
             (h1.into(), p1.parse().map_err(err1)?)
+        } else if secure {
+            (host.into(), SECURE_ADS_PORT)
         } else {
             (host.into(), ads::PORT)
         };
 
-        let netid = caps[2].parse().map_err(|_| err0())?;
-        let amsport = caps[4].parse().map_err(err1)?;
+        let netid = caps[3].parse().map_err(|_| err0())?;
+        let amsport = caps[5].parse().map_err(err1)?;
+
+        let mut tls = TlsOptions::default();
+        let mut symbol = None;
+        let mut socks = None;
+        let mut source = None;
+        for (key, value) in parse_query(caps.get(6).map(|m| m.as_str())) {
+            match key {
+                "source" => {
+                    let (netid, port) = value.rsplitn(2, ':').collect_tuple()
+                                             .map(|(p, n)| (n, p)).ok_or_else(err0)?;
+                    source = Some(ads::AmsAddr::new(netid.parse().map_err(|_| err0())?,
+                                                    port.parse().map_err(err1)?));
+                }
+                "socks5" => socks = Some(Socks5Proxy::new(value)?),
+                "symbol" if !value.is_empty() => symbol = Some(value.into()),
+                "ca" if secure => tls.ca = Some(value.into()),
+                "cert" if secure => tls.cert = Some(value.into()),
+                "key" if secure => tls.key = Some(value.into()),
+                "psk" if secure => {
+                    let (id, psk) = value.splitn(2, ':').collect_tuple().ok_or_else(err0)?;
+                    tls.psk = Some((id.into(), psk.into()));
+                }
+                _ => return Err(err0()),
+            }
+        }
+        if secure && tls.psk.is_none() && (tls.cert.is_none() || tls.key.is_none()) {
+            return Err(Error::InvalidAddress("ads+tls://...?cert=file&key=file or ?psk=id:key"));
+        }
 
         Ok(Self {
             host, port,
             target: ads::AmsAddr::new(netid, amsport),
+            tls: if secure { Some(tls) } else { None },
+            symbol,
+            area: M_AREA,
+            socks,
+            relay: None,
+            strategy: ReadStrategy::Unknown,
             tried_route: false,
+            source,
+            timeouts: Timeouts::default(),
+            cancel: Cancel::default(),
+            subscriptions: Subscriptions::default(),
+            dispatching: false,
             client: None,
         })
     }
 
+    /// Use the given timeouts instead of the default ones.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Fail requests while the given token is cancelled.  The `ads` crate's
+    /// connection can't be shut down from outside, so a pending request is
+    /// not aborted.
+    pub fn with_cancel(mut self, token: CancelToken) -> Self {
+        self.cancel = Cancel::new(token);
+        self
+    }
+
+    /// Connect if necessary, failing if the token is cancelled.
+    fn ensure_connected(&mut self) -> Result<()> {
+        self.cancel.check()?;
+        if self.client.is_none() {
+            self.reconnect()?;
+        }
+        Ok(())
+    }
+
     fn set_route(&self, src: ads::AmsNetId) {
         let myhost = format!("{}.{}.{}.{}", src.0[0], src.0[1], src.0[2], src.0[3]);
         let routename = format!("zapf-{}", myhost);
@@ -78,29 +226,184 @@ impl AdsProto {
             }
         }
     }
+
+    /// Look up the location of the image symbol, so that all accesses can be
+    /// made relative to it.  (Access via GetHandleByName handles would be
+    /// possible too, but doesn't allow for reading at an offset.)
+    fn resolve_symbol(&self, client: &ads::Client, symbol: &str) -> Result<Area> {
+        let mut buf = [0; 1024];
+        client.device(self.target).write_read(SYM_INFOBYNAMEEX, 0, symbol.as_bytes(), &mut buf)?;
+        let u32_at = |i: usize| u32::from_le_bytes([buf[i], buf[i+1], buf[i+2], buf[i+3]]);
+        let area = Area { group: u32_at(4), offset: u32_at(8), size: Some(u32_at(12) as usize) };
+        log::info!("symbol {} found at {:#x}:{:#x}, size {}",
+                   symbol, area.group, area.offset, u32_at(12));
+        Ok(area)
+    }
+
+    /// Subscribe to changes of the given range, which the PLC checks every
+    /// `cycle_time`.  Changes, including the initial contents, are sent to
+    /// the returned channel.
+    ///
+    /// Subscriptions are bound to the connection: the channel is closed when
+    /// the connection is closed or lost, and the caller has to subscribe again
+    /// after reconnecting.
+    pub fn subscribe(&mut self, addr: usize, len: usize, cycle_time: Duration)
+                     -> Result<mpsc::Receiver<Change>> {
+        self.ensure_connected()?;
+        self.check_range(addr, len)?;
+        let client = self.client.as_ref().unwrap();
+        if !self.dispatching {
+            let channel = client.get_notification_channel();
+            let subscriptions = self.subscriptions.clone();
+            thread::spawn(move || dispatch(channel.iter(), subscriptions));
+            self.dispatching = true;
+        }
+        let attrib = ads::notif::Attributes::new(
+            len, ads::notif::TransmissionMode::ServerOnChange, cycle_time, cycle_time);
+        let (sender, receiver) = mpsc::channel();
+        // keep the lock until the handle is registered, so that the
+        // dispatcher doesn't drop the first notification
+        let mut subscriptions = self.subscriptions.lock().unwrap_or_else(|e| e.into_inner());
+        let handle = client.device(self.target).add_notification(
+            self.area.group, self.area.offset + addr as u32, &attrib)?;
+        subscriptions.insert(handle, Subscription { addr, sender });
+        Ok(receiver)
+    }
+
+    /// Close all subscription channels, since the notification handles are
+    /// only valid for the current connection.
+    fn drop_subscriptions(&mut self) {
+        self.subscriptions.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        self.dispatching = false;
+    }
+
+    /// Read multiple (offset, data) chunks of the image area using a single
+    /// sum-up request.
+    fn sum_read(&self, chunks: &mut [(u32, &mut [u8])]) -> Result<()> {
+        let mut req = Vec::with_capacity(12 * chunks.len());
+        let mut total = 4 * chunks.len();
+        for (offset, data) in chunks.iter() {
+            req.extend_from_slice(&self.area.group.to_le_bytes());
+            req.extend_from_slice(&(self.area.offset + offset).to_le_bytes());
+            req.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            total += data.len();
+        }
+        let mut resp = vec![0; total];
+        let device = self.client.as_ref().unwrap().device(self.target);
+        let n = device.write_read(SUMUP_READ, chunks.len() as u32, &req, &mut resp)?;
+        if n != total {
+            return Err(Error::PLC(format!("sum-up read returned {} bytes, expected {}",
+                                          n, total)));
+        }
+        // first come all the result codes, then all the data
+        let (codes, mut rest) = resp.split_at(4 * chunks.len());
+        for (code, (offset, data)) in codes.chunks(4).zip(chunks.iter_mut()) {
+            let code = u32::from_le_bytes([code[0], code[1], code[2], code[3]]);
+            if code != 0 {
+                return Err(Error::PLC(format!("sum-up read at {} failed with ADS error {:#x}",
+                                              offset, code)));
+            }
+            data.copy_from_slice(&rest[..data.len()]);
+            rest = &rest[data.len()..];
+        }
+        Ok(())
+    }
+
+    /// Write multiple (offset, data) chunks of the image area using a single
+    /// sum-up request.
+    fn sum_write(&self, chunks: &[(usize, &[u8])]) -> Result<()> {
+        let total = chunks.iter().map(|(_, data)| data.len()).sum::<usize>();
+        let mut req = Vec::with_capacity(12 * chunks.len() + total);
+        for (offset, data) in chunks {
+            req.extend_from_slice(&self.area.group.to_le_bytes());
+            req.extend_from_slice(&(self.area.offset + *offset as u32).to_le_bytes());
+            req.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        }
+        for (_, data) in chunks {
+            req.extend_from_slice(data);
+        }
+        // the response only consists of the result codes
+        let mut resp = vec![0; 4 * chunks.len()];
+        let device = self.client.as_ref().unwrap().device(self.target);
+        let n = device.write_read(SUMUP_WRITE, chunks.len() as u32, &req, &mut resp)?;
+        if n != resp.len() {
+            return Err(Error::PLC(format!("sum-up write returned {} bytes, expected {}",
+                                          n, resp.len())));
+        }
+        for (code, (offset, _)) in resp.chunks(4).zip(chunks) {
+            let code = u32::from_le_bytes([code[0], code[1], code[2], code[3]]);
+            if code != 0 {
+                return Err(Error::PLC(format!("sum-up write at {} failed with ADS error {:#x}",
+                                              offset, code)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Read a large block as a series of sum-up requests.
+    fn read_sumup(&self, addr: usize, data: &mut [u8]) -> Result<()> {
+        let mut offset = addr as u32;
+        let mut chunks = data.chunks_mut(SUMUP_CHUNK).map(|chunk| {
+            let res = (offset, chunk);
+            offset += res.1.len() as u32;
+            res
+        }).collect_vec();
+        for group in chunks.chunks_mut(SUMUP_MAX) {
+            self.sum_read(group)?;
+        }
+        Ok(())
+    }
+
+    fn check_range(&self, addr: usize, len: usize) -> Result<()> {
+        // without a symbol, the index offset is the limit
+        let size = self.area.size.unwrap_or((u32::MAX - self.area.offset) as usize);
+        check_length(addr, len, size)
+    }
 }
 
 impl Protocol for AdsProto {
-    fn get_offsets() -> &'static [usize] {
+    fn get_offsets(&self) -> &'static [usize] {
         &[0]
     }
 
     fn set_offset(&mut self, _: usize) { }
 
     fn connect(&mut self) -> Result<()> {
+        self.cancel.check()?;
+        if self.tls.is_some() {
+            // the ads crate only implements plain AMS/TCP
+            return Err(Error::Unsupported("Secure ADS (TLS) connections are not \
+                                           implemented by the ads crate"));
+        }
         let timeouts = ads::Timeouts {
-            connect: Some(CONNECT_TIMEOUT),
-            write: Some(WRITE_TIMEOUT),
-            read: Some(READ_TIMEOUT),
+            connect: Some(self.timeouts.connect),
+            write: Some(self.timeouts.write),
+            read: Some(self.timeouts.read),
         };
-        let client = ads::Client::new((self.host.as_str(), self.port), timeouts, None)?;
+        // close the old connection first, so that its source port is free for
+        // the new client to bind again
+        self.drop_subscriptions();
+        self.client = None;
+        let client = if let Some(proxy) = &self.socks {
+            if self.relay.is_none() {
+                self.relay = Some(Socks5Relay::start(proxy.clone(), &self.host, self.port,
+                                                     self.timeouts)?);
+            }
+            let port = self.relay.as_ref().unwrap().port();
+            ads::Client::new(("127.0.0.1", port), timeouts, self.source)?
+        } else {
+            ads::Client::new((self.host.as_str(), self.port), timeouts, self.source)?
+        };
+        // reuse the same source on reconnect, some routers don't free ports
+        self.source = Some(client.source());
 
         let info = match client.device(self.target).get_info() {
             Ok(info) => info,
             Err(ads::Error::Io(_, ioe)) if
                 ioe.kind() == std::io::ErrorKind::UnexpectedEof &&
                 !self.tried_route &&
-                self.port == ads::PORT =>
+                self.port == ads::PORT &&
+                self.socks.is_none() =>
             {
                 log::warn!("connection aborted, trying to set a route...");
                 self.tried_route = true;
@@ -110,29 +413,139 @@ impl Protocol for AdsProto {
             Err(e) => Err(e)?,
         };
 
-        self.client = Some(client);
         log::info!("connected to {} {}.{}.{}", info.name,
                    info.major, info.minor, info.version);
+        if let Some(symbol) = &self.symbol {
+            self.area = self.resolve_symbol(&client, symbol)?;
+        }
+        self.client = Some(client);
         Ok(())
     }
 
+    fn preflight(&mut self) -> Preflight {
+        let mut report = Preflight::default();
+        // connecting also checks the route by querying the device info
+        if self.socks.is_some() || report.tcp(&self.host, self.port, self.timeouts.connect) {
+            report.connect_and_read(self);
+        }
+        report
+    }
+
     fn disconnect(&mut self) {
+        self.drop_subscriptions();
         self.client = None;
     }
 
     fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
-        if self.client.is_none() {
-            self.reconnect()?;
+        self.ensure_connected()?;
+        self.check_range(addr, data.len())?;
+        if data.len() > SUMUP_CHUNK && self.strategy == ReadStrategy::SumUp {
+            return self.read_sumup(addr, data);
+        }
+        let device = self.client.as_ref().unwrap().device(self.target);
+        match device.read_exact(self.area.group, self.area.offset + addr as u32, data) {
+            Ok(()) => {
+                if data.len() > SUMUP_CHUNK {
+                    self.strategy = ReadStrategy::Single;
+                }
+                Ok(())
+            }
+            // some targets (older TC2) reject large reads, but can do the
+            // same using sum-up requests
+            Err(e) if data.len() > SUMUP_CHUNK && self.strategy == ReadStrategy::Unknown &&
+                !matches!(e, ads::Error::Io(..)) =>
+            {
+                log::info!("large read failed ({}), trying sum-up requests", e);
+                self.read_sumup(addr, data)?;
+                self.strategy = ReadStrategy::SumUp;
+                Ok(())
+            }
+            Err(e) => Err(e.into())
         }
+    }
+
+    fn read_ranges(&mut self, ranges: &[(usize, usize)]) -> Result<Vec<Vec<u8>>> {
+        self.ensure_connected()?;
+        for &(addr, length) in ranges {
+            self.check_range(addr, length)?;
+        }
+        let mut result = ranges.iter().map(|&(_, length)| vec![0; length]).collect_vec();
+        let res = {
+            let mut chunks = ranges.iter().zip(&mut result)
+                                   .map(|(&(addr, _), data)| (addr as u32, &mut data[..]))
+                                   .collect_vec();
+            chunks.chunks_mut(SUMUP_MAX).try_for_each(|group| self.sum_read(group))
+        };
+        match res {
+            Ok(()) => Ok(result),
+            // target doesn't support sum-up requests
+            Err(Error::ADS(e)) if !matches!(e, ads::Error::Io(..)) => {
+                log::info!("sum-up read failed ({}), reading ranges one by one", e);
+                ranges.iter().map(|&(addr, length)| self.read(addr, length)).collect()
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write_ranges(&mut self, writes: &[(usize, &[u8])]) -> Result<()> {
+        self.ensure_connected()?;
+        for &(addr, data) in writes {
+            self.check_range(addr, data.len())?;
+        }
+        // fall back to single writes only if the target rejects sum-up
+        // requests as a whole, otherwise some writes may be done already
+        let mut groups = writes.chunks(SUMUP_MAX);
+        match groups.next().map(|group| self.sum_write(group)) {
+            Some(Err(Error::ADS(e))) if !matches!(e, ads::Error::Io(..)) => {
+                log::info!("sum-up write failed ({}), writing ranges one by one", e);
+                writes.iter().try_for_each(|&(addr, data)| self.write(addr, data))
+            }
+            Some(Err(e)) => Err(e),
+            _ => groups.try_for_each(|group| self.sum_write(group)),
+        }
+    }
+
+    fn write_bit(&mut self, addr: usize, bit: u8, value: bool) -> Result<()> {
+        check_bit(bit)?;
+        if self.area.group != ads::index::PLC_RW_M {
+            // bit access only exists for the %M area
+            return self.write_masked(addr, &[(value as u8) << bit], &[1 << bit]);
+        }
+        self.ensure_connected()?;
+        self.check_range(addr, 1)?;
+        let bitaddr = 8 * (self.area.offset + addr as u32) + bit as u32;
         let device = self.client.as_ref().unwrap().device(self.target);
-        device.read_exact(ads::index::PLC_RW_M, addr as u32, data).map_err(Into::into)
+        device.write(PLC_RW_MX, bitaddr, &[value as u8]).map_err(Into::into)
     }
 
     fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
-        if self.client.is_none() {
-            self.reconnect()?;
-        }
+        self.ensure_connected()?;
+        self.check_range(addr, data.len())?;
         let device = self.client.as_ref().unwrap().device(self.target);
-        device.write(ads::index::PLC_RW_M, addr as u32, data).map_err(Into::into)
+        device.write(self.area.group, self.area.offset + addr as u32, data)
+              .map_err(Into::into)
+    }
+}
+
+/// Distribute incoming notifications to the subscribers, until the client's
+/// notification channel is closed.
+fn dispatch(notifications: impl Iterator<Item=ads::notif::Notification>,
+            subscriptions: Subscriptions) {
+    for notif in notifications {
+        let mut subscriptions = subscriptions.lock().unwrap_or_else(|e| e.into_inner());
+        for sample in notif.samples() {
+            if let Some(sub) = subscriptions.get(&sample.handle) {
+                let since_epoch = sample.timestamp.saturating_sub(FILETIME_OFFSET);
+                let change = Change {
+                    addr: sub.addr,
+                    time: UNIX_EPOCH + Duration::from_nanos(100 * since_epoch),
+                    data: sample.data.to_vec(),
+                };
+                if sub.sender.send(change).is_err() {
+                    // receiver is gone, the PLC keeps sending until reconnect
+                    subscriptions.remove(&sample.handle);
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,211 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! A bounded log of the writes and errors of a connection.
+//!
+//! Only the last `capacity` entries are kept; an export hook can be set to
+//! ship every entry elsewhere as it is recorded.
+
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+use crate::Result;
+use crate::proto::{Preflight, Protocol, check_bit};
+
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    pub time: SystemTime,
+    pub addr: usize,
+    /// The data written, empty for failed reads.  For masked writes, only
+    /// the bits in `mask` are set.
+    pub data: Vec<u8>,
+    /// The bits that were written, empty if all bits of `data` were.
+    pub mask: Vec<u8>,
+    /// None if the write was successful.
+    pub error: Option<String>,
+}
+
+pub struct Audited<P> {
+    inner: P,
+    entries: VecDeque<AuditEntry>,
+    capacity: usize,
+    dropped: u64,
+    export: Option<Box<dyn FnMut(&AuditEntry) + Send>>,
+}
+
+impl<P: Protocol> Audited<P> {
+    pub fn new(inner: P, capacity: usize) -> Self {
+        Self { inner, entries: VecDeque::with_capacity(capacity), capacity, dropped: 0,
+               export: None }
+    }
+
+    /// Call the given hook for each new entry.
+    pub fn with_export(mut self, hook: impl FnMut(&AuditEntry) + Send + 'static) -> Self {
+        self.export = Some(Box::new(hook));
+        self
+    }
+
+    /// Return the retained entries, oldest first.
+    pub fn entries(&self) -> impl Iterator<Item=&AuditEntry> {
+        self.entries.iter()
+    }
+
+    /// Return the number of entries that were discarded to stay within the
+    /// capacity.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    fn record<T>(&mut self, addr: usize, data: &[u8], mask: &[u8], result: &Result<T>) {
+        let entry = AuditEntry {
+            time: SystemTime::now(),
+            addr,
+            data: if mask.is_empty() {
+                data.to_vec()
+            } else {
+                data.iter().zip(mask).map(|(d, m)| d & m).collect()
+            },
+            mask: mask.to_vec(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        };
+        if let Some(hook) = &mut self.export {
+            hook(&entry);
+        }
+        if self.capacity == 0 {
+            self.dropped += 1;
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+            self.dropped += 1;
+        }
+        self.entries.push_back(entry);
+    }
+}
+
+impl<P> Audited<P> {
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+}
+
+impl<P: Protocol> Protocol for Audited<P> {
+    fn get_offsets(&self) -> &'static [usize] {
+        self.inner.get_offsets()
+    }
+
+    fn set_offset(&mut self, offset: usize) {
+        self.inner.set_offset(offset);
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        self.inner.connect()
+    }
+
+    fn disconnect(&mut self) {
+        self.inner.disconnect();
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.inner.reconnect()
+    }
+
+    fn preflight(&mut self) -> Preflight {
+        self.inner.preflight()
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        let result = self.inner.read_into(addr, data);
+        if result.is_err() {
+            self.record(addr, &[], &[], &result);
+        }
+        result
+    }
+
+    fn read_ranges(&mut self, ranges: &[(usize, usize)]) -> Result<Vec<Vec<u8>>> {
+        let result = self.inner.read_ranges(ranges);
+        if result.is_err() {
+            for &(addr, _) in ranges {
+                self.record(addr, &[], &[], &result);
+            }
+        }
+        result
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        let result = self.inner.write(addr, data);
+        self.record(addr, data, &[], &result);
+        result
+    }
+
+    fn write_masked(&mut self, addr: usize, data: &[u8], mask: &[u8]) -> Result<()> {
+        let result = self.inner.write_masked(addr, data, mask);
+        self.record(addr, data, mask, &result);
+        result
+    }
+
+    fn write_ranges(&mut self, writes: &[(usize, &[u8])]) -> Result<()> {
+        let result = self.inner.write_ranges(writes);
+        for &(addr, data) in writes {
+            self.record(addr, data, &[], &result);
+        }
+        result
+    }
+
+    fn write_read(&mut self, addr: usize, data: &[u8], read_addr: usize,
+                  result: &mut [u8]) -> Result<()> {
+        let res = self.inner.write_read(addr, data, read_addr, result);
+        self.record(addr, data, &[], &res);
+        res
+    }
+
+    fn write_bit(&mut self, addr: usize, bit: u8, value: bool) -> Result<()> {
+        check_bit(bit)?;
+        let result = self.inner.write_bit(addr, bit, value);
+        self.record(addr, &[(value as u8) << bit], &[1 << bit], &result);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakePlc, call_overrides};
+
+    #[test]
+    fn forwards_overrides() {
+        let mut proto = Audited::new(FakePlc::new(4), 16);
+        call_overrides(&mut proto);
+        // read_bit is left to the default, which audits errors via read_into
+        assert_eq!(proto.inner().calls(), ["read_into", "write_bit", "write_masked",
+                                           "read_ranges", "write_ranges", "write_read"]);
+    }
+}
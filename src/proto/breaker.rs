@@ -0,0 +1,202 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! Failing fast while a PLC is unreachable.
+//!
+//! After a number of consecutive connection errors, the breaker "opens" and
+//! all operations fail immediately for a cool-down period, instead of each
+//! waiting for the connect timeout.  After that, the next operation is let
+//! through as a probe: if it succeeds, the breaker closes again, otherwise
+//! it stays open for another cool-down period.
+
+use std::time::{Duration, Instant};
+
+use crate::{Error, Result};
+use crate::proto::{Preflight, Protocol};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Operations are passed through.
+    Closed,
+    /// Operations fail immediately until the given time.
+    Open(Instant),
+    /// The cool-down is over, the next operation decides the new state.
+    HalfOpen,
+}
+
+pub struct CircuitBreaker<P> {
+    inner: P,
+    threshold: usize,
+    cool_down: Duration,
+    failures: usize,
+    open_until: Option<Instant>,
+}
+
+impl<P: Protocol> CircuitBreaker<P> {
+    /// Create a breaker that opens after `threshold` consecutive connection
+    /// errors and stays open for `cool_down`.
+    pub fn new(inner: P, threshold: usize, cool_down: Duration) -> Self {
+        Self { inner, threshold: threshold.max(1), cool_down, failures: 0, open_until: None }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        match self.open_until {
+            None => BreakerState::Closed,
+            Some(until) if Instant::now() < until => BreakerState::Open(until),
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    /// Close the breaker, e.g. when the PLC is known to be back.
+    pub fn reset(&mut self) {
+        self.failures = 0;
+        self.open_until = None;
+    }
+
+    fn run<T>(&mut self, op: impl FnOnce(&mut P) -> Result<T>) -> Result<T> {
+        if let BreakerState::Open(until) = self.state() {
+            // not a connection error, so that retrying wrappers give up
+            return Err(Error::CircuitOpen(until.saturating_duration_since(Instant::now())));
+        }
+        let result = op(&mut self.inner);
+        match &result {
+            Err(e) if e.is_connection_error() => {
+                self.failures += 1;
+                if self.failures >= self.threshold {
+                    if self.open_until.is_none() {
+                        log::warn!("{} consecutive connection errors, failing fast for {:?}",
+                                   self.failures, self.cool_down);
+                    }
+                    self.open_until = Some(Instant::now() + self.cool_down);
+                }
+            }
+            _ => {
+                if self.open_until.is_some() {
+                    log::info!("connection is back, closing circuit breaker");
+                }
+                self.reset();
+            }
+        }
+        result
+    }
+}
+
+impl<P> CircuitBreaker<P> {
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+}
+
+impl<P: Protocol> Protocol for CircuitBreaker<P> {
+    fn get_offsets(&self) -> &'static [usize] {
+        self.inner.get_offsets()
+    }
+
+    fn set_offset(&mut self, offset: usize) {
+        self.inner.set_offset(offset);
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        self.run(|p| p.connect())
+    }
+
+    fn disconnect(&mut self) {
+        self.inner.disconnect();
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.run(|p| p.reconnect())
+    }
+
+    fn preflight(&mut self) -> Preflight {
+        // diagnostics should always reach the PLC
+        self.inner.preflight()
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        self.run(|p| p.read_into(addr, data))
+    }
+
+    fn read_ranges(&mut self, ranges: &[(usize, usize)]) -> Result<Vec<Vec<u8>>> {
+        self.run(|p| p.read_ranges(ranges))
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        self.run(|p| p.write(addr, data))
+    }
+
+    fn write_masked(&mut self, addr: usize, data: &[u8], mask: &[u8]) -> Result<()> {
+        self.run(|p| p.write_masked(addr, data, mask))
+    }
+
+    fn write_ranges(&mut self, writes: &[(usize, &[u8])]) -> Result<()> {
+        self.run(|p| p.write_ranges(writes))
+    }
+
+    fn write_read(&mut self, addr: usize, data: &[u8], read_addr: usize,
+                  result: &mut [u8]) -> Result<()> {
+        self.run(|p| p.write_read(addr, data, read_addr, result))
+    }
+
+    fn read_bit(&mut self, addr: usize, bit: u8) -> Result<bool> {
+        self.run(|p| p.read_bit(addr, bit))
+    }
+
+    fn write_bit(&mut self, addr: usize, bit: u8, value: bool) -> Result<()> {
+        self.run(|p| p.write_bit(addr, bit, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakePlc, OVERRIDES, call_overrides};
+
+    #[test]
+    fn open_breaker() {
+        let mut breaker = CircuitBreaker::new(FakePlc::new(4), 1, Duration::from_secs(60));
+        let reset = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        breaker.inner_mut().fail_next(reset.into());
+        assert!(breaker.read(0, 2).unwrap_err().is_connection_error());
+        let err = breaker.read(0, 2).unwrap_err();
+        assert!(matches!(err, Error::CircuitOpen(_)));
+        assert!(!err.is_connection_error());
+        breaker.reset();
+        assert_eq!(breaker.read(0, 2).unwrap(), [0, 0]);
+    }
+
+    #[test]
+    fn forwards_overrides() {
+        let mut breaker = CircuitBreaker::new(FakePlc::new(4), 1, Duration::from_secs(60));
+        call_overrides(&mut breaker);
+        assert_eq!(breaker.inner().calls(), OVERRIDES);
+    }
+}
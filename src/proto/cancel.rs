@@ -0,0 +1,158 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! Aborting pending requests from another thread.
+//!
+//! Backends that have their own sockets (HTTP, SLMP, FINS over TCP,
+//! PILS-direct and Modbus) accept a `CancelToken` with `with_cancel`.
+//! Cancelling shuts down their sockets, so that a blocked read or write
+//! returns immediately, and fails all further requests until the token is
+//! reset.
+//!
+//! The ADS backend also accepts a token, but the `ads` crate's connection
+//! can't be shut down from outside: there, cancelling only fails the further
+//! requests, and a pending one runs until it is done or times out.
+//!
+//! In no backend a pending connect can be interrupted, since the standard
+//! library has no cancellable connect.  It is bounded by the connect timeout,
+//! and fails with `Error::Cancelled` if the token was cancelled meanwhile.
+
+use std::collections::HashMap;
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::{Error, Result};
+
+#[derive(Default)]
+struct Shared {
+    cancelled: AtomicBool,
+    // incremented for each cancel
+    generation: AtomicUsize,
+    next_id: AtomicUsize,
+    streams: Mutex<HashMap<usize, TcpStream>>,
+}
+
+/// A token to cancel requests; clones of it refer to the same token.
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    shared: Arc<Shared>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Abort all pending requests, and fail new ones until `reset`.
+    pub fn cancel(&self) {
+        self.shared.cancelled.store(true, Ordering::SeqCst);
+        self.shared.generation.fetch_add(1, Ordering::SeqCst);
+        for stream in self.streams().values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Allow requests again.  Backends whose connection was shut down
+    /// reconnect on the next request.
+    pub fn reset(&self) {
+        self.shared.cancelled.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.shared.cancelled.load(Ordering::SeqCst)
+    }
+
+    fn streams(&self) -> MutexGuard<'_, HashMap<usize, TcpStream>> {
+        self.shared.streams.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Cancellation support for a backend connection.
+#[derive(Default)]
+pub(crate) struct Cancel {
+    token: Option<CancelToken>,
+    // handle to unregister the current stream, and the token's generation
+    // when it was registered
+    watch: Option<(usize, usize)>,
+}
+
+impl Cancel {
+    pub(crate) fn new(token: CancelToken) -> Self {
+        Self { token: Some(token), watch: None }
+    }
+
+    /// Check if a token is used at all.
+    pub(crate) fn is_active(&self) -> bool {
+        self.token.is_some()
+    }
+
+    /// Return an error if the token has been cancelled.
+    pub(crate) fn check(&self) -> Result<()> {
+        match &self.token {
+            Some(token) if token.is_cancelled() => Err(Error::Cancelled),
+            _ => Ok(())
+        }
+    }
+
+    /// Register a new connection's stream to shut down on cancel, failing if
+    /// the token was cancelled while connecting.
+    pub(crate) fn watch(&mut self, stream: &TcpStream) -> Result<()> {
+        self.release();
+        if let Some(token) = &self.token {
+            let id = token.shared.next_id.fetch_add(1, Ordering::Relaxed);
+            let generation = token.shared.generation.load(Ordering::SeqCst);
+            token.streams().insert(id, stream.try_clone()?);
+            self.watch = Some((id, generation));
+            // don't miss a cancel that happened while connecting
+            if token.is_cancelled() {
+                self.release();
+                return Err(Error::Cancelled);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check if the registered stream has been shut down by a cancel.
+    pub(crate) fn is_shut_down(&self) -> bool {
+        match (&self.token, self.watch) {
+            (Some(token), Some((_, generation))) =>
+                token.shared.generation.load(Ordering::SeqCst) != generation,
+            _ => false,
+        }
+    }
+
+    /// Unregister the stream, which must be done when disconnecting, since
+    /// the token's handle would keep the socket open.
+    pub(crate) fn release(&mut self) {
+        if let (Some(token), Some((id, _))) = (&self.token, self.watch.take()) {
+            token.streams().remove(&id);
+        }
+    }
+}
+
+impl Drop for Cancel {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
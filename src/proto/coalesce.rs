@@ -0,0 +1,187 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! Coalescing reads of a poll cycle into fewer requests.
+//!
+//! Reads are queued during the cycle, and executed together on `flush`,
+//! merging overlapping and nearby ranges into a single request each.  The
+//! results are then picked up with the ticket that `queue` returned.
+
+use crate::{Error, Result};
+use crate::proto::{Preflight, Protocol, merge_ranges};
+
+/// Handle for the result of a queued read.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Ticket(usize);
+
+pub struct Coalescing<P> {
+    inner: P,
+    max_gap: usize,
+    pending: Vec<(usize, usize)>,
+    results: Vec<Option<Result<Vec<u8>>>>,
+}
+
+impl<P: Protocol> Coalescing<P> {
+    /// Create the wrapper, merging ranges that are at most `max_gap` bytes
+    /// apart.
+    pub fn new(inner: P, max_gap: usize) -> Self {
+        Self { inner, max_gap, pending: Vec::new(), results: Vec::new() }
+    }
+
+    /// Queue a read for the next flush.
+    pub fn queue(&mut self, addr: usize, length: usize) -> Ticket {
+        self.pending.push((addr, length));
+        Ticket(self.results.len() + self.pending.len() - 1)
+    }
+
+    /// Execute all queued reads.  Errors are reported per ticket; if a merged
+    /// read fails, its ranges are retried one by one.
+    pub fn flush(&mut self) {
+        let ranges = std::mem::take(&mut self.pending);
+        let base = self.results.len();
+        self.results.resize_with(base + ranges.len(), || None);
+        let spans = merge_ranges(&ranges, self.max_gap);
+        if !ranges.is_empty() {
+            log::debug!("coalesced {} reads into {}", ranges.len(), spans.len());
+        }
+        for (start, length, indices) in spans {
+            match self.inner.read(start, length) {
+                Ok(data) => for i in indices {
+                    let (addr, length) = ranges[i];
+                    let data = data[addr - start..addr - start + length].to_vec();
+                    self.results[base + i] = Some(Ok(data));
+                },
+                Err(_) => for i in indices {
+                    let (addr, length) = ranges[i];
+                    self.results[base + i] = Some(self.inner.read(addr, length));
+                },
+            }
+        }
+    }
+
+    /// Get the result of a queued read, flushing first if necessary.  Each
+    /// result can only be taken once.
+    pub fn take(&mut self, ticket: Ticket) -> Result<Vec<u8>> {
+        if ticket.0 >= self.results.len() {
+            self.flush();
+        }
+        let result = self.results.get_mut(ticket.0).and_then(Option::take)
+            .unwrap_or_else(|| Err(Error::PLC("result of queued read already taken".into())));
+        // forget the results once all have been taken
+        if self.pending.is_empty() && self.results.iter().all(Option::is_none) {
+            self.results.clear();
+        }
+        result
+    }
+}
+
+impl<P> Coalescing<P> {
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+}
+
+impl<P: Protocol> Protocol for Coalescing<P> {
+    fn get_offsets(&self) -> &'static [usize] {
+        self.inner.get_offsets()
+    }
+
+    fn set_offset(&mut self, offset: usize) {
+        self.inner.set_offset(offset);
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        self.inner.connect()
+    }
+
+    fn disconnect(&mut self) {
+        self.inner.disconnect();
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.inner.reconnect()
+    }
+
+    fn preflight(&mut self) -> Preflight {
+        self.inner.preflight()
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        self.inner.read_into(addr, data)
+    }
+
+    fn read_ranges(&mut self, ranges: &[(usize, usize)]) -> Result<Vec<Vec<u8>>> {
+        let tickets = ranges.iter().map(|&(addr, length)| self.queue(addr, length))
+                                   .collect::<Vec<_>>();
+        self.flush();
+        tickets.into_iter().map(|ticket| self.take(ticket)).collect()
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        self.inner.write(addr, data)
+    }
+
+    fn write_masked(&mut self, addr: usize, data: &[u8], mask: &[u8]) -> Result<()> {
+        self.inner.write_masked(addr, data, mask)
+    }
+
+    fn write_ranges(&mut self, writes: &[(usize, &[u8])]) -> Result<()> {
+        self.inner.write_ranges(writes)
+    }
+
+    fn write_read(&mut self, addr: usize, data: &[u8], read_addr: usize,
+                  result: &mut [u8]) -> Result<()> {
+        self.inner.write_read(addr, data, read_addr, result)
+    }
+
+    fn read_bit(&mut self, addr: usize, bit: u8) -> Result<bool> {
+        self.inner.read_bit(addr, bit)
+    }
+
+    fn write_bit(&mut self, addr: usize, bit: u8, value: bool) -> Result<()> {
+        self.inner.write_bit(addr, bit, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakePlc, call_overrides};
+
+    #[test]
+    fn forwards_overrides() {
+        let mut proto = Coalescing::new(FakePlc::new(4), 1);
+        call_overrides(&mut proto);
+        // the two ranges are read at once
+        assert_eq!(proto.inner().calls(), ["read_bit", "write_bit", "write_masked", "read_into",
+                                           "write_ranges", "write_read"]);
+    }
+}
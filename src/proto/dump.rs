@@ -0,0 +1,188 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! Logging of all transferred data as hex dumps, for debugging byte order
+//! and offset problems without access to the PLC network.
+//!
+//! The dumps are logged at trace level with the target `zapf::dump`, or
+//! written to a separate sink if one is given.
+
+use std::fmt::Write as _;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::Result;
+use crate::proto::{Preflight, Protocol, check_bit};
+
+/// Format data as lines of 16 bytes with offset and ASCII columns.
+pub fn hexdump(data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        let _ = write!(out, "{:04x}  ", 16 * i);
+        for j in 0..16 {
+            match line.get(j) {
+                Some(b) => { let _ = write!(out, "{:02x} ", b); }
+                None => out.push_str("   "),
+            }
+        }
+        out.push(' ');
+        out.extend(line.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' {
+            b as char
+        } else {
+            '.'
+        }));
+        out.push('\n');
+    }
+    out
+}
+
+pub struct Dumped<P> {
+    inner: P,
+    sink: Option<Box<dyn Write + Send>>,
+}
+
+impl<P: Protocol> Dumped<P> {
+    pub fn new(inner: P) -> Self {
+        Self { inner, sink: None }
+    }
+
+    /// Write the dumps to the given sink instead of the log.
+    pub fn with_sink(mut self, sink: impl Write + Send + 'static) -> Self {
+        self.sink = Some(Box::new(sink));
+        self
+    }
+
+    fn dump(&mut self, what: &str, addr: usize, data: &[u8]) {
+        if let Some(sink) = &mut self.sink {
+            let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            let _ = write!(sink, "{}.{:06} {} {} bytes at {}\n{}", time.as_secs(),
+                           time.subsec_micros(), what, data.len(), addr, hexdump(data));
+            let _ = sink.flush();
+        } else if log::log_enabled!(target: "zapf::dump", log::Level::Trace) {
+            log::trace!(target: "zapf::dump", "{} {} bytes at {}\n{}",
+                        what, data.len(), addr, hexdump(data));
+        }
+    }
+}
+
+impl<P> Dumped<P> {
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+}
+
+impl<P: Protocol> Protocol for Dumped<P> {
+    fn get_offsets(&self) -> &'static [usize] {
+        self.inner.get_offsets()
+    }
+
+    fn set_offset(&mut self, offset: usize) {
+        self.inner.set_offset(offset);
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        self.inner.connect()
+    }
+
+    fn disconnect(&mut self) {
+        self.inner.disconnect();
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.inner.reconnect()
+    }
+
+    fn preflight(&mut self) -> Preflight {
+        self.inner.preflight()
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        self.inner.read_into(addr, data)?;
+        self.dump("read", addr, data);
+        Ok(())
+    }
+
+    fn read_ranges(&mut self, ranges: &[(usize, usize)]) -> Result<Vec<Vec<u8>>> {
+        let result = self.inner.read_ranges(ranges)?;
+        for (&(addr, _), data) in ranges.iter().zip(&result) {
+            self.dump("read", addr, data);
+        }
+        Ok(result)
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        self.dump("write", addr, data);
+        self.inner.write(addr, data)
+    }
+
+    fn write_masked(&mut self, addr: usize, data: &[u8], mask: &[u8]) -> Result<()> {
+        self.dump("write masked", addr, data);
+        self.dump("with mask", addr, mask);
+        self.inner.write_masked(addr, data, mask)
+    }
+
+    fn write_bit(&mut self, addr: usize, bit: u8, value: bool) -> Result<()> {
+        check_bit(bit)?;
+        self.dump("write masked", addr, &[(value as u8) << bit]);
+        self.dump("with mask", addr, &[1 << bit]);
+        self.inner.write_bit(addr, bit, value)
+    }
+
+    fn write_ranges(&mut self, writes: &[(usize, &[u8])]) -> Result<()> {
+        for &(addr, data) in writes {
+            self.dump("write", addr, data);
+        }
+        self.inner.write_ranges(writes)
+    }
+
+    fn write_read(&mut self, addr: usize, data: &[u8], read_addr: usize,
+                  result: &mut [u8]) -> Result<()> {
+        self.dump("write", addr, data);
+        self.inner.write_read(addr, data, read_addr, result)?;
+        self.dump("read", read_addr, result);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakePlc, call_overrides};
+
+    #[test]
+    fn forwards_overrides() {
+        let mut proto = Dumped::new(FakePlc::new(4)).with_sink(std::io::sink());
+        call_overrides(&mut proto);
+        // read_bit is left to the default, so that the byte is dumped
+        assert_eq!(proto.inner().calls(), ["read_into", "write_bit", "write_masked",
+                                           "read_ranges", "write_ranges", "write_read"]);
+    }
+}
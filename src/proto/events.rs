@@ -0,0 +1,231 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! Notification about changes of the connection state.
+//!
+//! The callback can be used to update a UI directly, or to forward the
+//! events through a channel.
+
+use crate::Result;
+use crate::proto::{Preflight, Protocol};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnEvent {
+    /// The connection was (re)established.
+    Connected,
+    /// The connection was lost or closed.
+    Disconnected { reason: String },
+    /// A reconnect is being tried, either explicitly or by the first request
+    /// after the connection was lost.
+    Reconnecting,
+}
+
+pub struct Observed<P> {
+    inner: P,
+    callback: Box<dyn FnMut(&ConnEvent) + Send>,
+    connected: bool,
+    // a request will have to reconnect first
+    lost: bool,
+}
+
+impl<P: Protocol> Observed<P> {
+    pub fn new(inner: P, callback: impl FnMut(&ConnEvent) + Send + 'static) -> Self {
+        Self { inner, callback: Box::new(callback), connected: false, lost: false }
+    }
+
+    /// Return if the connection is currently considered established.
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn set_connected(&mut self) {
+        if !self.connected {
+            self.connected = true;
+            self.lost = false;
+            (self.callback)(&ConnEvent::Connected);
+        }
+    }
+
+    fn set_disconnected(&mut self, reason: String) {
+        if self.connected {
+            self.connected = false;
+            self.lost = true;
+            (self.callback)(&ConnEvent::Disconnected { reason });
+        }
+    }
+
+    /// Announce the implicit reconnect of the backends, once per outage.
+    fn begin(&mut self) {
+        if self.lost {
+            self.lost = false;
+            (self.callback)(&ConnEvent::Reconnecting);
+        }
+    }
+
+    /// Track the connection state from the result of an operation.
+    fn track<T>(&mut self, result: Result<T>) -> Result<T> {
+        match &result {
+            Ok(_) => self.set_connected(),
+            Err(e) if e.is_connection_error() => self.set_disconnected(e.to_string()),
+            Err(_) => (),
+        }
+        result
+    }
+
+    fn track_connect(&mut self, result: Result<()>) -> Result<()> {
+        // any error while connecting means we are not connected
+        match &result {
+            Ok(()) => self.set_connected(),
+            Err(e) => self.set_disconnected(e.to_string()),
+        }
+        result
+    }
+}
+
+impl<P> Observed<P> {
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+}
+
+impl<P: Protocol> Protocol for Observed<P> {
+    fn get_offsets(&self) -> &'static [usize] {
+        self.inner.get_offsets()
+    }
+
+    fn set_offset(&mut self, offset: usize) {
+        self.inner.set_offset(offset);
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        self.begin();
+        let result = self.inner.connect();
+        self.track_connect(result)
+    }
+
+    fn disconnect(&mut self) {
+        self.inner.disconnect();
+        self.set_disconnected("disconnect requested".into());
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.lost = false;
+        (self.callback)(&ConnEvent::Reconnecting);
+        let result = self.inner.reconnect();
+        self.track_connect(result)
+    }
+
+    fn preflight(&mut self) -> Preflight {
+        self.inner.preflight()
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        self.begin();
+        let result = self.inner.read_into(addr, data);
+        self.track(result)
+    }
+
+    fn read_ranges(&mut self, ranges: &[(usize, usize)]) -> Result<Vec<Vec<u8>>> {
+        self.begin();
+        let result = self.inner.read_ranges(ranges);
+        self.track(result)
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        self.begin();
+        let result = self.inner.write(addr, data);
+        self.track(result)
+    }
+
+    fn write_masked(&mut self, addr: usize, data: &[u8], mask: &[u8]) -> Result<()> {
+        self.begin();
+        let result = self.inner.write_masked(addr, data, mask);
+        self.track(result)
+    }
+
+    fn write_ranges(&mut self, writes: &[(usize, &[u8])]) -> Result<()> {
+        self.begin();
+        let result = self.inner.write_ranges(writes);
+        self.track(result)
+    }
+
+    fn write_read(&mut self, addr: usize, data: &[u8], read_addr: usize,
+                  result: &mut [u8]) -> Result<()> {
+        self.begin();
+        let res = self.inner.write_read(addr, data, read_addr, result);
+        self.track(res)
+    }
+
+    fn read_bit(&mut self, addr: usize, bit: u8) -> Result<bool> {
+        self.begin();
+        let result = self.inner.read_bit(addr, bit);
+        self.track(result)
+    }
+
+    fn write_bit(&mut self, addr: usize, bit: u8, value: bool) -> Result<()> {
+        self.begin();
+        let result = self.inner.write_bit(addr, bit, value);
+        self.track(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use super::*;
+    use crate::testing::{FakePlc, OVERRIDES, call_overrides};
+
+    #[test]
+    fn reconnecting_after_loss() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        let mut obs = Observed::new(FakePlc::new(4),
+                                    move |ev| sink.lock().unwrap().push(ev.clone()));
+        obs.read(0, 2).unwrap();
+        let reset = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        obs.inner_mut().fail_next(reset.into());
+        assert!(obs.read(0, 2).is_err());
+        obs.read(0, 2).unwrap();
+        assert!(matches!(&events.lock().unwrap()[..], [
+            ConnEvent::Connected,
+            ConnEvent::Disconnected { .. },
+            ConnEvent::Reconnecting,
+            ConnEvent::Connected,
+        ]));
+    }
+
+    #[test]
+    fn forwards_overrides() {
+        let mut obs = Observed::new(FakePlc::new(4), |_| ());
+        call_overrides(&mut obs);
+        assert_eq!(obs.inner().calls(), OVERRIDES);
+    }
+}
@@ -0,0 +1,207 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! Failover between two connections to the same PLC.
+
+use std::time::{Duration, Instant};
+
+use crate::Result;
+use crate::proto::{Preflight, Protocol};
+
+/// When to go back to the primary connection after failing over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailBack {
+    /// Stay on the backup until it fails itself.
+    Never,
+    /// Try the primary again once the backup has been used for this long.
+    After(Duration),
+}
+
+pub struct FailoverProto<P> {
+    protos: [P; 2],
+    active: usize,
+    since: Instant,
+    fail_back: FailBack,
+    on_switch: Option<Box<dyn FnMut(bool) + Send>>,
+}
+
+impl<P: Protocol> FailoverProto<P> {
+    pub fn new(primary: P, backup: P) -> Self {
+        Self {
+            protos: [primary, backup],
+            active: 0,
+            since: Instant::now(),
+            fail_back: FailBack::Never,
+            on_switch: None,
+        }
+    }
+
+    pub fn with_fail_back(mut self, fail_back: FailBack) -> Self {
+        self.fail_back = fail_back;
+        self
+    }
+
+    /// Call the given callback after each switch, with true if the backup
+    /// connection is used from now on.
+    pub fn on_switch(mut self, callback: impl FnMut(bool) + Send + 'static) -> Self {
+        self.on_switch = Some(Box::new(callback));
+        self
+    }
+
+    /// Return true if the backup connection is currently in use.
+    pub fn on_backup(&self) -> bool {
+        self.active == 1
+    }
+
+    fn switch(&mut self) {
+        self.protos[self.active].disconnect();
+        self.active = 1 - self.active;
+        self.since = Instant::now();
+        log::warn!("switching to {} connection",
+                   if self.active == 0 { "primary" } else { "backup" });
+        if let Some(callback) = &mut self.on_switch {
+            callback(self.active == 1);
+        }
+    }
+
+    fn check_fail_back(&mut self) {
+        if let FailBack::After(time) = self.fail_back {
+            if self.active == 1 && self.since.elapsed() >= time {
+                self.switch();
+            }
+        }
+    }
+
+    /// Run an operation on the active connection, switching over and trying
+    /// again on connection errors.
+    fn run<T>(&mut self, mut op: impl FnMut(&mut P) -> Result<T>) -> Result<T> {
+        self.check_fail_back();
+        match op(&mut self.protos[self.active]) {
+            Err(e) if e.is_connection_error() => {
+                log::warn!("error on active connection: {}", e);
+                self.switch();
+                op(&mut self.protos[self.active])
+            }
+            res => res,
+        }
+    }
+}
+
+impl<P: Protocol> Protocol for FailoverProto<P> {
+    fn get_offsets(&self) -> &'static [usize] {
+        self.protos[self.active].get_offsets()
+    }
+
+    fn set_offset(&mut self, offset: usize) {
+        for proto in &mut self.protos {
+            proto.set_offset(offset);
+        }
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        match self.protos[self.active].connect() {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                log::warn!("connecting failed: {}", e);
+                self.switch();
+                self.protos[self.active].connect()
+            }
+        }
+    }
+
+    fn disconnect(&mut self) {
+        for proto in &mut self.protos {
+            proto.disconnect();
+        }
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        match self.protos[self.active].reconnect() {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                log::warn!("reconnecting failed: {}", e);
+                self.switch();
+                self.protos[self.active].reconnect()
+            }
+        }
+    }
+
+    fn preflight(&mut self) -> Preflight {
+        // check both connections, a broken backup should be noticed early
+        let mut report = Preflight::default();
+        for (name, proto) in ["primary", "backup"].iter().zip(&mut self.protos) {
+            for (check, result) in proto.preflight().checks {
+                report.checks.push((format!("{}: {}", name, check), result));
+            }
+        }
+        self.protos[1 - self.active].disconnect();
+        report
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        self.run(|p| p.read_into(addr, data))
+    }
+
+    fn read_ranges(&mut self, ranges: &[(usize, usize)]) -> Result<Vec<Vec<u8>>> {
+        self.run(|p| p.read_ranges(ranges))
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        self.run(|p| p.write(addr, data))
+    }
+
+    fn write_masked(&mut self, addr: usize, data: &[u8], mask: &[u8]) -> Result<()> {
+        self.run(|p| p.write_masked(addr, data, mask))
+    }
+
+    fn write_ranges(&mut self, writes: &[(usize, &[u8])]) -> Result<()> {
+        self.run(|p| p.write_ranges(writes))
+    }
+
+    fn write_read(&mut self, addr: usize, data: &[u8], read_addr: usize,
+                  result: &mut [u8]) -> Result<()> {
+        self.run(|p| p.write_read(addr, data, read_addr, result))
+    }
+
+    fn read_bit(&mut self, addr: usize, bit: u8) -> Result<bool> {
+        self.run(|p| p.read_bit(addr, bit))
+    }
+
+    fn write_bit(&mut self, addr: usize, bit: u8, value: bool) -> Result<()> {
+        self.run(|p| p.write_bit(addr, bit, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakePlc, OVERRIDES, call_overrides};
+
+    #[test]
+    fn forwards_overrides() {
+        let mut proto = FailoverProto::new(FakePlc::new(4), FakePlc::new(4));
+        call_overrides(&mut proto);
+        assert_eq!(proto.protos[0].calls(), OVERRIDES);
+        assert!(proto.protos[1].calls().is_empty());
+    }
+}
@@ -0,0 +1,329 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! Access to Omron PLCs via FINS/TCP or FINS/UDP.  The PILS image is located
+//! in the DM area, starting at the word given in the address.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+
+use crate::{Error, Result};
+use crate::proto::{ErrorLog, Preflight, Protocol, Timeouts, check_length, parse_query,
+                   probe_request_size};
+use crate::proto::cancel::{Cancel, CancelToken};
+
+use regex::Regex;
+use once_cell::sync::Lazy;
+
+static FINS_ADDR_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"fins(\+udp)?://(.+?)(?::(\d+))?/D(\d+)(?:\?(.*))?$")
+        .expect("invalid regex")
+});
+const FINS_ADDR_FMT: &str = "fins[+udp]://host[:port]/Dword[?node=n&src=n&mtu=auto|words]";
+
+const FINS_PORT: u16 = 9600;
+
+/// Maximum number of words in a single memory area read or write, unless
+/// lowered by the `mtu` option.
+const MAX_WORDS: usize = 990;
+
+/// Memory area code for DM in word access.
+const AREA_DM: u8 = 0x82;
+
+enum Transport {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+}
+
+pub struct FinsProto {
+    host: String,
+    port: u16,
+    udp: bool,
+    base: u16,
+    dest_node: u8,
+    src_node: u8,
+    sid: u8,
+    transport: Option<Transport>,
+    timeouts: Timeouts,
+    cancel: Cancel,
+    errors: ErrorLog,
+    offset: usize,
+    max_words: usize,
+    tune: bool,
+}
+
+impl FinsProto {
+    pub fn new(addr: &str) -> Result<Self> {
+        let err0 = || Error::InvalidAddress(FINS_ADDR_FMT);
+        let err1 = |_| Error::InvalidAddress(FINS_ADDR_FMT);
+        let caps = FINS_ADDR_RE.captures(addr).ok_or_else(err0)?;
+        let udp = caps.get(1).is_some();
+        let host = caps[2].into();
+        let port = if let Some(port) = caps.get(3) {
+            port.as_str().parse().map_err(err1)?
+        } else {
+            FINS_PORT
+        };
+        let base = caps[4].parse().map_err(err1)?;
+        // node numbers are only configurable for UDP; with TCP they are
+        // assigned during the handshake
+        let (mut dest_node, mut src_node) = (0, 0);
+        let mut max_words = MAX_WORDS;
+        let mut tune = false;
+        for (key, value) in parse_query(caps.get(5).map(|m| m.as_str())) {
+            match key {
+                "mtu" if value == "auto" => tune = true,
+                "mtu" => max_words = value.parse::<usize>().map_err(err1)?.min(MAX_WORDS).max(1),
+                "node" if udp => dest_node = value.parse().map_err(err1)?,
+                "src" if udp => src_node = value.parse().map_err(err1)?,
+                _ => return Err(err0()),
+            }
+        }
+
+        Ok(Self { host, port, udp, base, dest_node, src_node, sid: 0,
+                  transport: None, offset: 0, max_words, tune,
+                  timeouts: Timeouts::default(), cancel: Cancel::default(),
+                  errors: ErrorLog::default() })
+    }
+
+    /// Use the given timeouts instead of the default ones.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Abort pending requests when the given token is cancelled.
+    pub fn with_cancel(mut self, token: CancelToken) -> Self {
+        self.cancel = Cancel::new(token);
+        self
+    }
+
+    fn tcp_handshake(&mut self, stream: &mut TcpStream) -> Result<()> {
+        let mut req = *b"FINS\0\0\0\x0c\0\0\0\0\0\0\0\0\0\0\0\0";
+        req[19] = self.src_node;
+        stream.write_all(&req)?;
+        let mut reply = [0; 24];
+        stream.read_exact(&mut reply)?;
+        if &reply[..4] != b"FINS" || reply[11] != 1 {
+            return Err(Error::FINS("invalid handshake response".into()));
+        }
+        let error = u32::from_be_bytes([reply[12], reply[13], reply[14], reply[15]]);
+        if error != 0 {
+            return Err(Error::FINS(format!("handshake error code {:#x}", error)));
+        }
+        self.src_node = reply[19];
+        self.dest_node = reply[23];
+        Ok(())
+    }
+
+    fn command(&mut self, code: [u8; 2], word: usize, count: usize,
+               payload: &[u8]) -> Result<Vec<u8>> {
+        self.cancel.check()?;
+        if self.transport.is_none() || self.cancel.is_shut_down() {
+            self.reconnect()?;
+        }
+        let word = self.base as usize + word;
+        if word + count > 0xFFFF {
+            return Err(Error::FINS("address too big".into()));
+        }
+        self.sid = self.sid.wrapping_add(1);
+        let mut frame = vec![0x80, 0x00, 0x02, 0x00, self.dest_node, 0x00,
+                             0x00, self.src_node, 0x00, self.sid,
+                             code[0], code[1], AREA_DM];
+        frame.extend_from_slice(&(word as u16).to_be_bytes());
+        frame.push(0);
+        frame.extend_from_slice(&(count as u16).to_be_bytes());
+        frame.extend_from_slice(payload);
+
+        let sid = self.sid;
+        let result = match self.transport.as_mut().unwrap() {
+            Transport::Tcp(stream) => Self::tcp_transact(stream, &frame),
+            Transport::Udp(socket) => Self::udp_transact(socket, &frame, sid),
+        };
+        let reply = match result {
+            Ok(reply) => reply,
+            Err(ioe) => {
+                self.disconnect();
+                self.errors.report(format!("during FINS request: {}", ioe));
+                return Err(Error::Wrapped(Box::new(ioe.into()),
+                                          if code[1] == 1 { "read" } else { "write" }));
+            }
+        };
+        if reply.len() < 14 || reply[10..12] != code {
+            self.disconnect();
+            return Err(Error::FINS("invalid response frame".into()));
+        }
+        // the "network relay error" bit of the main code is not an error
+        let end_code = u16::from_be_bytes([reply[12] & 0x7F, reply[13]]);
+        if end_code != 0 {
+            return Err(Error::FINS(format!("end code {:#06x}", end_code)));
+        }
+        Ok(reply[14..].to_vec())
+    }
+
+    fn tcp_transact(stream: &mut TcpStream, frame: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut header = *b"FINS\0\0\0\0\0\0\0\x02\0\0\0\0";
+        header[4..8].copy_from_slice(&(8 + frame.len() as u32).to_be_bytes());
+        stream.write_all(&header)?;
+        stream.write_all(frame)?;
+        stream.read_exact(&mut header)?;
+        let length = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        let mut reply = vec![0; (length as usize).saturating_sub(8)];
+        stream.read_exact(&mut reply)?;
+        Ok(reply)
+    }
+
+    fn udp_transact(socket: &UdpSocket, frame: &[u8], sid: u8) -> std::io::Result<Vec<u8>> {
+        socket.send(frame)?;
+        let mut buf = [0; 2048];
+        loop {
+            let n = socket.recv(&mut buf)?;
+            // skip late replies to previous (timed out) requests
+            if n >= 10 && buf[9] == sid {
+                return Ok(buf[..n].to_vec());
+            }
+        }
+    }
+
+    /// Size of the image in bytes, words in the DM area have 16-bit addresses.
+    fn image_size(&self) -> usize {
+        (2 * (0xFFFF - self.base as usize)).saturating_sub(self.offset)
+    }
+
+    fn read_words(&mut self, word: usize, data: &mut [u8]) -> Result<()> {
+        for (i, chunk) in data.chunks_mut(2 * self.max_words).enumerate() {
+            let reply = self.command([0x01, 0x01], word + i * self.max_words, chunk.len() / 2, &[])?;
+            if reply.len() != chunk.len() {
+                return Err(Error::FINS("unexpected reply size".into()));
+            }
+            // words are transmitted big-endian, the image is little-endian
+            for (dst, src) in chunk.chunks_mut(2).zip(reply.chunks(2)) {
+                dst[0] = src[1];
+                dst[1] = src[0];
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Protocol for FinsProto {
+    fn get_offsets(&self) -> &'static [usize] {
+        &[0]
+    }
+
+    fn set_offset(&mut self, offset: usize) {
+        self.offset = offset;
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        self.cancel.check()?;
+        let addr: SocketAddr = (self.host.as_str(), self.port).to_socket_addrs()?.next()
+            .ok_or_else(|| Error::InvalidAddress(FINS_ADDR_FMT))?;
+        let transport = if self.udp {
+            let local: SocketAddr = if addr.is_ipv4() {
+                ([0u8; 4], 0).into()
+            } else {
+                ([0u16; 8], 0).into()
+            };
+            let socket = UdpSocket::bind(local)?;
+            socket.set_read_timeout(Some(self.timeouts.read))?;
+            socket.set_write_timeout(Some(self.timeouts.write))?;
+            socket.connect(addr)?;
+            Transport::Udp(socket)
+        } else {
+            let mut stream = TcpStream::connect_timeout(&addr, self.timeouts.connect)?;
+            stream.set_read_timeout(Some(self.timeouts.read))?;
+            stream.set_write_timeout(Some(self.timeouts.write))?;
+            stream.set_nodelay(true)?;
+            self.cancel.watch(&stream)?;
+            self.tcp_handshake(&mut stream)?;
+            Transport::Tcp(stream)
+        };
+        self.transport = Some(transport);
+
+        self.errors.flush();
+        log::info!("connected to {}:{}", self.host, self.port);
+        if self.tune {
+            // failed probes can reconnect, which must not probe again
+            self.tune = false;
+            self.max_words = MAX_WORDS;
+            let mut buf = vec![0; 2 * MAX_WORDS];
+            self.max_words = probe_request_size(1, MAX_WORDS, 1, |n| {
+                self.read_words(0, &mut buf[..2*n]).is_ok()
+            });
+            self.tune = true;
+        }
+        Ok(())
+    }
+
+    fn preflight(&mut self) -> Preflight {
+        let mut report = Preflight::default();
+        // UDP has no connection that could be checked beforehand
+        if self.udp || report.tcp(&self.host, self.port, self.timeouts.connect) {
+            report.connect_and_read(self);
+        }
+        report
+    }
+
+    fn disconnect(&mut self) {
+        self.transport = None;
+        self.cancel.release();
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        check_length(addr, data.len(), self.image_size())?;
+        let addr = self.offset + addr;
+        let first = addr / 2;
+        let last = (addr + data.len() + 1) / 2;
+        let mut buf = vec![0; 2 * (last - first)];
+        self.read_words(first, &mut buf)?;
+        let skip = addr % 2;
+        data.copy_from_slice(&buf[skip..skip + data.len()]);
+        Ok(())
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        check_length(addr, data.len(), self.image_size())?;
+        let addr = self.offset + addr;
+        let first = addr / 2;
+        let last = (addr + data.len() + 1) / 2;
+        let mut buf = vec![0; 2 * (last - first)];
+        let skip = addr % 2;
+        // complete partially written words with the current contents
+        if skip != 0 {
+            self.read_words(first, &mut buf[..2])?;
+        }
+        if (addr + data.len()) % 2 != 0 {
+            let n = buf.len();
+            self.read_words(last - 1, &mut buf[n-2..])?;
+        }
+        buf[skip..skip + data.len()].copy_from_slice(data);
+        for word in buf.chunks_mut(2) {
+            word.swap(0, 1);
+        }
+        for (i, chunk) in buf.chunks(2 * self.max_words).enumerate() {
+            self.command([0x01, 0x02], first + i * self.max_words, chunk.len() / 2, chunk)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! Client-side forcing of image contents.
+//!
+//! Forced byte ranges are overlaid onto all data read from the PLC, e.g. to
+//! simulate the value of a sensor that is physically absent.  Writes are
+//! always passed through to the PLC unchanged.
+
+use crate::Result;
+use crate::proto::{Preflight, Protocol};
+
+pub struct Forcing<P> {
+    inner: P,
+    // sorted by address, never overlapping
+    forced: Vec<(usize, Vec<u8>)>,
+}
+
+impl<P> Forcing<P> {
+    pub fn new(inner: P) -> Self {
+        Self { inner, forced: Vec::new() }
+    }
+
+    /// Force the bytes at `addr` to read as `data`, replacing any previously
+    /// forced bytes in that range.
+    pub fn force(&mut self, addr: usize, data: &[u8]) {
+        self.unforce(addr, data.len());
+        log::warn!("forcing {} bytes at address {}", data.len(), addr);
+        let pos = self.forced.iter().position(|f| f.0 > addr).unwrap_or(self.forced.len());
+        self.forced.insert(pos, (addr, data.into()));
+    }
+
+    /// Remove forcing from all bytes in the given range.
+    pub fn unforce(&mut self, addr: usize, len: usize) {
+        let end = addr + len;
+        let mut result = Vec::with_capacity(self.forced.len());
+        for (faddr, fdata) in self.forced.drain(..) {
+            let fend = faddr + fdata.len();
+            if fend <= addr || faddr >= end {
+                result.push((faddr, fdata));
+                continue;
+            }
+            // keep the parts outside the range
+            if faddr < addr {
+                result.push((faddr, fdata[..addr - faddr].into()));
+            }
+            if fend > end {
+                result.push((end, fdata[end - faddr..].into()));
+            }
+        }
+        self.forced = result;
+    }
+
+    /// Remove all forcing.
+    pub fn clear(&mut self) {
+        self.forced.clear();
+    }
+
+    /// Check if any byte in the given range is currently forced.
+    pub fn is_forced(&self, addr: usize, len: usize) -> bool {
+        self.forced.iter().any(|(faddr, fdata)| *faddr < addr + len && faddr + fdata.len() > addr)
+    }
+
+    /// Iterate over all forced ranges as (address, data).
+    pub fn forced(&self) -> impl Iterator<Item=(usize, &[u8])> {
+        self.forced.iter().map(|(addr, data)| (*addr, &data[..]))
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+
+    /// Apply the forced bytes to data read from `addr`.
+    fn overlay(&self, addr: usize, data: &mut [u8]) {
+        let end = addr + data.len();
+        for (faddr, fdata) in &self.forced {
+            let fend = faddr + fdata.len();
+            if *faddr < end && fend > addr {
+                let from = addr.max(*faddr);
+                let to = end.min(fend);
+                data[from - addr..to - addr].copy_from_slice(&fdata[from - faddr..to - faddr]);
+            }
+        }
+    }
+}
+
+impl<P: Protocol> Protocol for Forcing<P> {
+    fn get_offsets(&self) -> &'static [usize] {
+        self.inner.get_offsets()
+    }
+
+    fn set_offset(&mut self, offset: usize) {
+        self.inner.set_offset(offset);
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        self.inner.connect()
+    }
+
+    fn disconnect(&mut self) {
+        self.inner.disconnect();
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.inner.reconnect()
+    }
+
+    fn preflight(&mut self) -> Preflight {
+        self.inner.preflight()
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        self.inner.read_into(addr, data)?;
+        self.overlay(addr, data);
+        Ok(())
+    }
+
+    fn read_ranges(&mut self, ranges: &[(usize, usize)]) -> Result<Vec<Vec<u8>>> {
+        let mut result = self.inner.read_ranges(ranges)?;
+        for (&(addr, _), data) in ranges.iter().zip(&mut result) {
+            self.overlay(addr, data);
+        }
+        Ok(result)
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        self.inner.write(addr, data)
+    }
+
+    fn write_ranges(&mut self, writes: &[(usize, &[u8])]) -> Result<()> {
+        self.inner.write_ranges(writes)
+    }
+
+    fn write_read(&mut self, addr: usize, data: &[u8], read_addr: usize,
+                  result: &mut [u8]) -> Result<()> {
+        self.inner.write_read(addr, data, read_addr, result)?;
+        self.overlay(read_addr, result);
+        Ok(())
+    }
+
+    fn write_masked(&mut self, addr: usize, data: &[u8], mask: &[u8]) -> Result<()> {
+        // must not write back forced values
+        self.inner.write_masked(addr, data, mask)
+    }
+
+    fn write_bit(&mut self, addr: usize, bit: u8, value: bool) -> Result<()> {
+        self.inner.write_bit(addr, bit, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakePlc, call_overrides};
+
+    #[test]
+    fn forwards_overrides() {
+        let mut proto = Forcing::new(FakePlc::new(4));
+        call_overrides(&mut proto);
+        // read_bit is left to the default, which reads through the overlay
+        assert_eq!(proto.inner().calls(), ["read_into", "write_bit", "write_masked",
+                                           "read_ranges", "write_ranges", "write_read"]);
+    }
+}
@@ -0,0 +1,262 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! Access to the PLC image via a simple REST gateway.
+//!
+//! The gateway is expected to serve `GET <path>/mem?addr=N&len=M`, returning
+//! the raw bytes as the response body, and `POST <path>/mem?addr=N` with the
+//! raw bytes to write as the request body.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+use crate::{Error, Result};
+use crate::proto::{ErrorLog, Preflight, Protocol, Timeouts, check_length};
+use crate::proto::cancel::{Cancel, CancelToken};
+
+use itertools::Itertools;
+use regex::Regex;
+use once_cell::sync::Lazy;
+
+static HTTP_ADDR_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"http://([^/:]+)(?::(\d+))?(/.*?)?/?$")
+        .expect("invalid regex")
+});
+const HTTP_ADDR_FMT: &str = "http://host[:port][/path]";
+
+const HTTP_PORT: u16 = 80;
+/// Longest response body accepted beyond the requested length, e.g. for
+/// error messages.
+const MAX_EXTRA_BODY: usize = 4096;
+
+pub struct HttpProto {
+    host: String,
+    port: u16,
+    path: String,
+    stream: Option<BufReader<TcpStream>>,
+    timeouts: Timeouts,
+    cancel: Cancel,
+    errors: ErrorLog,
+    offset: usize,
+}
+
+impl HttpProto {
+    pub fn new(addr: &str) -> Result<Self> {
+        let err0 = || Error::InvalidAddress(HTTP_ADDR_FMT);
+        let err1 = |_| Error::InvalidAddress(HTTP_ADDR_FMT);
+        let caps = HTTP_ADDR_RE.captures(addr).ok_or_else(err0)?;
+        let host = caps[1].into();
+        let port = if let Some(port) = caps.get(2) {
+            port.as_str().parse().map_err(err1)?
+        } else {
+            HTTP_PORT
+        };
+        let path = caps.get(3).map_or("", |p| p.as_str()).into();
+
+        Ok(Self { host, port, path, stream: None, offset: 0, timeouts: Timeouts::default(),
+                  cancel: Cancel::default(), errors: ErrorLog::default() })
+    }
+
+    /// Use the given timeouts instead of the default ones.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Abort pending requests when the given token is cancelled.
+    pub fn with_cancel(mut self, token: CancelToken) -> Self {
+        self.cancel = Cancel::new(token);
+        self
+    }
+
+    fn request(&mut self, method: &str, query: String, body: &[u8], expect: usize)
+               -> Result<Vec<u8>> {
+        self.cancel.check()?;
+        if self.stream.is_none() || self.cancel.is_shut_down() {
+            self.reconnect()?;
+        }
+        match self.transact(method, &query, body, expect + MAX_EXTRA_BODY) {
+            Ok((data, keep_alive)) => {
+                if !keep_alive {
+                    self.disconnect();
+                }
+                Ok(data)
+            }
+            Err(Error::IO(ioe)) => {
+                self.disconnect();
+                self.errors.report(format!("during HTTP {}: {}", method, ioe));
+                Err(Error::Wrapped(Box::new(ioe.into()),
+                                   if method == "GET" { "read" } else { "write" }))
+            }
+            Err(e) => {
+                // we don't know in which state the connection is now
+                self.disconnect();
+                Err(e)
+            }
+        }
+    }
+
+    fn transact(&mut self, method: &str, query: &str, body: &[u8], limit: usize)
+                -> Result<(Vec<u8>, bool)> {
+        let stream = self.stream.as_mut().unwrap();
+        let head = format!("{} {}/mem?{} HTTP/1.1\r\n\
+                            Host: {}:{}\r\n\
+                            Connection: keep-alive\r\n\
+                            Content-Type: application/octet-stream\r\n\
+                            Content-Length: {}\r\n\r\n",
+                           method, self.path, query, self.host, self.port, body.len());
+        stream.get_mut().write_all(head.as_bytes())?;
+        stream.get_mut().write_all(body)?;
+
+        let mut line = String::new();
+        stream.read_line(&mut line)?;
+        let status = line.split_whitespace().nth(1)
+            .and_then(|s| s.parse::<u16>().ok())
+            .ok_or_else(|| Error::HTTP(format!("invalid status line {:?}", line.trim())))?;
+
+        let mut length = None;
+        let mut chunked = false;
+        let mut keep_alive = true;
+        loop {
+            line.clear();
+            if stream.read_line(&mut line)? == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.splitn(2, ':').collect_tuple() {
+                let value = value.trim();
+                match &*name.trim().to_ascii_lowercase() {
+                    "content-length" => length = value.parse::<usize>().ok(),
+                    "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+                    "connection" => keep_alive = !value.eq_ignore_ascii_case("close"),
+                    _ => ()
+                }
+            }
+        }
+
+        // don't trust the announced sizes for allocating
+        let too_long = |n: usize| Error::HTTP(format!(
+            "gateway announced {} bytes, expected at most {}", n, limit));
+        let mut data = Vec::new();
+        if chunked {
+            loop {
+                line.clear();
+                stream.read_line(&mut line)?;
+                let size = usize::from_str_radix(line.trim().split(';').next().unwrap_or(""), 16)
+                    .map_err(|_| Error::HTTP("invalid chunk header".into()))?;
+                let start = data.len();
+                if start + size > limit {
+                    return Err(too_long(start + size));
+                }
+                data.resize(start + size, 0);
+                stream.read_exact(&mut data[start..])?;
+                line.clear();
+                stream.read_line(&mut line)?;
+                if size == 0 {
+                    break;
+                }
+            }
+        } else if let Some(length) = length {
+            if length > limit {
+                return Err(too_long(length));
+            }
+            data.resize(length, 0);
+            stream.read_exact(&mut data)?;
+        } else {
+            // no framing information, the body extends until the server closes
+            stream.take(limit as u64 + 1).read_to_end(&mut data)?;
+            if data.len() > limit {
+                return Err(too_long(data.len()));
+            }
+            keep_alive = false;
+        }
+
+        if !(200..300).contains(&status) {
+            return Err(Error::HTTP(format!("gateway returned status {}: {}", status,
+                                           String::from_utf8_lossy(&data).trim())));
+        }
+        Ok((data, keep_alive))
+    }
+}
+
+impl Protocol for HttpProto {
+    fn get_offsets(&self) -> &'static [usize] {
+        &[0]
+    }
+
+    fn set_offset(&mut self, offset: usize) {
+        self.offset = offset;
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        self.cancel.check()?;
+        let addr = (self.host.as_str(), self.port).to_socket_addrs()?.next()
+            .ok_or_else(|| Error::InvalidAddress(HTTP_ADDR_FMT))?;
+        let stream = TcpStream::connect_timeout(&addr, self.timeouts.connect)?;
+        stream.set_read_timeout(Some(self.timeouts.read))?;
+        stream.set_write_timeout(Some(self.timeouts.write))?;
+        stream.set_nodelay(true)?;
+        self.cancel.watch(&stream)?;
+        self.stream = Some(BufReader::new(stream));
+
+        self.errors.flush();
+        log::info!("connected to {}:{}", self.host, self.port);
+        Ok(())
+    }
+
+    fn preflight(&mut self) -> Preflight {
+        let mut report = Preflight::default();
+        if report.tcp(&self.host, self.port, self.timeouts.connect) {
+            report.connect_and_read(self);
+        }
+        report
+    }
+
+    fn disconnect(&mut self) {
+        self.stream = None;
+        self.cancel.release();
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        // the gateway checks the image size itself
+        check_length(addr, data.len(), usize::MAX)?;
+        let query = format!("addr={}&len={}", self.offset + addr, data.len());
+        let result = self.request("GET", query, &[], data.len())?;
+        if result.len() != data.len() {
+            return Err(Error::HTTP(format!("gateway returned {} bytes instead of {}",
+                                           result.len(), data.len())));
+        }
+        data.copy_from_slice(&result);
+        Ok(())
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        check_length(addr, data.len(), usize::MAX)?;
+        let query = format!("addr={}", self.offset + addr);
+        self.request("POST", query, data, 0)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! Keeping idle connections alive.
+//!
+//! A background thread reads the first bytes of the image (the magic) when
+//! the connection has not been used for a while, and reconnects if that
+//! fails.  This way, a broken connection is noticed and repaired before the
+//! next real request has to wait for the reconnect.
+//!
+//! After an explicit `disconnect`, no probing is done until the next
+//! `connect` or `reconnect`, so that the connection stays closed.
+
+use std::sync::{Arc, Mutex, MutexGuard, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::Result;
+use crate::proto::{Preflight, Protocol};
+
+struct Shared<P> {
+    inner: P,
+    last_used: Instant,
+    // set by an explicit disconnect, which should not be undone by probing
+    disconnected: bool,
+}
+
+pub struct KeepAlive<P> {
+    shared: Arc<Mutex<Shared<P>>>,
+    // dropping this stops the background thread
+    _stop: mpsc::Sender<()>,
+}
+
+fn lock<P>(shared: &Mutex<Shared<P>>) -> MutexGuard<'_, Shared<P>> {
+    // a panic during a request doesn't leave the protocol in an unusable state
+    shared.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl<P: Protocol + Send + 'static> KeepAlive<P> {
+    /// Wrap a protocol, checking the connection after `interval` of
+    /// inactivity.
+    pub fn new(inner: P, interval: Duration) -> Self {
+        let shared = Arc::new(Mutex::new(Shared { inner, last_used: Instant::now(),
+                                                    disconnected: false }));
+        let (stop, stopped) = mpsc::channel::<()>();
+        let shared2 = shared.clone();
+        thread::spawn(move || {
+            let mut wait = interval;
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(wait) {
+                let mut shared = lock(&shared2);
+                let idle = shared.last_used.elapsed();
+                if idle < interval {
+                    wait = interval - idle;
+                    continue;
+                }
+                wait = interval;
+                if shared.disconnected {
+                    continue;
+                }
+                if let Err(e) = shared.inner.read(0, 4) {
+                    log::warn!("keepalive read failed ({}), reconnecting", e);
+                    shared.inner.disconnect();
+                    if let Err(e) = shared.inner.reconnect() {
+                        log::warn!("keepalive reconnect failed: {}", e);
+                    }
+                }
+                shared.last_used = Instant::now();
+            }
+        });
+        Self { shared, _stop: stop }
+    }
+}
+
+impl<P: Protocol> KeepAlive<P> {
+    fn run<T>(&mut self, op: impl FnOnce(&mut P) -> T) -> T {
+        let mut shared = lock(&self.shared);
+        shared.last_used = Instant::now();
+        op(&mut shared.inner)
+    }
+}
+
+impl<P: Protocol> Protocol for KeepAlive<P> {
+    fn get_offsets(&self) -> &'static [usize] {
+        lock(&self.shared).inner.get_offsets()
+    }
+
+    fn set_offset(&mut self, offset: usize) {
+        self.run(|p| p.set_offset(offset))
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        lock(&self.shared).disconnected = false;
+        self.run(|p| p.connect())
+    }
+
+    fn disconnect(&mut self) {
+        lock(&self.shared).disconnected = true;
+        self.run(|p| p.disconnect())
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        lock(&self.shared).disconnected = false;
+        self.run(|p| p.reconnect())
+    }
+
+    fn preflight(&mut self) -> Preflight {
+        self.run(|p| p.preflight())
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        self.run(|p| p.read_into(addr, data))
+    }
+
+    fn read_ranges(&mut self, ranges: &[(usize, usize)]) -> Result<Vec<Vec<u8>>> {
+        self.run(|p| p.read_ranges(ranges))
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        self.run(|p| p.write(addr, data))
+    }
+
+    fn write_masked(&mut self, addr: usize, data: &[u8], mask: &[u8]) -> Result<()> {
+        self.run(|p| p.write_masked(addr, data, mask))
+    }
+
+    fn write_ranges(&mut self, writes: &[(usize, &[u8])]) -> Result<()> {
+        self.run(|p| p.write_ranges(writes))
+    }
+
+    fn write_read(&mut self, addr: usize, data: &[u8], read_addr: usize,
+                  result: &mut [u8]) -> Result<()> {
+        self.run(|p| p.write_read(addr, data, read_addr, result))
+    }
+
+    fn read_bit(&mut self, addr: usize, bit: u8) -> Result<bool> {
+        self.run(|p| p.read_bit(addr, bit))
+    }
+
+    fn write_bit(&mut self, addr: usize, bit: u8, value: bool) -> Result<()> {
+        self.run(|p| p.write_bit(addr, bit, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakePlc, OVERRIDES, call_overrides};
+
+    #[test]
+    fn forwards_overrides() {
+        let mut proto = KeepAlive::new(FakePlc::new(4), Duration::from_secs(60));
+        call_overrides(&mut proto);
+        assert_eq!(lock(&proto.shared).inner.calls(), OVERRIDES);
+    }
+}
@@ -0,0 +1,228 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! Pluggable interceptors, for logging, metrics or data transformation
+//! without a dedicated wrapper type.
+
+use crate::{Error, Result};
+use crate::proto::{Preflight, Protocol, check_bit};
+
+/// A request as seen by an interceptor.
+#[derive(Debug, Clone, Copy)]
+pub enum Request<'a> {
+    Connect,
+    Read { addr: usize, len: usize },
+    Write { addr: usize, data: &'a [u8] },
+    WriteMasked { addr: usize, data: &'a [u8], mask: &'a [u8] },
+}
+
+pub trait Interceptor {
+    /// Called before each request.  Returning an error fails the request
+    /// without passing it on.
+    fn before_request(&mut self, _req: &Request<'_>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called after a successful request, with the data read (empty for
+    /// other requests), which can be modified.
+    fn after_response(&mut self, _req: &Request<'_>, _data: &mut [u8]) { }
+
+    /// Called if the request failed.
+    fn on_error(&mut self, _req: &Request<'_>, _error: &Error) { }
+}
+
+/// A protocol wrapped by a stack of interceptors.
+///
+/// `before_request` is called in the order the interceptors were added,
+/// `after_response` and `on_error` in reverse order.
+pub struct Layered<P> {
+    inner: P,
+    layers: Vec<Box<dyn Interceptor + Send>>,
+}
+
+impl<P: Protocol> Layered<P> {
+    pub fn new(inner: P) -> Self {
+        Self { inner, layers: Vec::new() }
+    }
+
+    /// Add an interceptor on top of the existing ones.
+    pub fn with(mut self, layer: impl Interceptor + Send + 'static) -> Self {
+        self.layers.push(Box::new(layer));
+        self
+    }
+
+    fn before(&mut self, req: &Request<'_>) -> Result<()> {
+        self.layers.iter_mut().try_for_each(|layer| layer.before_request(req))
+    }
+
+    fn after(&mut self, req: &Request<'_>, result: Result<()>, data: &mut [u8]) -> Result<()> {
+        match &result {
+            Ok(()) => self.layers.iter_mut().rev().for_each(|l| l.after_response(req, data)),
+            Err(e) => self.layers.iter_mut().rev().for_each(|l| l.on_error(req, e)),
+        }
+        result
+    }
+
+    fn run(&mut self, req: Request<'_>, data: &mut [u8],
+           op: impl FnOnce(&mut P, &mut [u8]) -> Result<()>) -> Result<()> {
+        self.before(&req)?;
+        let result = op(&mut self.inner, data);
+        self.after(&req, result, data)
+    }
+}
+
+impl<P> Layered<P> {
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+}
+
+impl<P: Protocol> Protocol for Layered<P> {
+    fn get_offsets(&self) -> &'static [usize] {
+        self.inner.get_offsets()
+    }
+
+    fn set_offset(&mut self, offset: usize) {
+        self.inner.set_offset(offset);
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        self.run(Request::Connect, &mut [], |p, _| p.connect())
+    }
+
+    fn disconnect(&mut self) {
+        self.inner.disconnect();
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.run(Request::Connect, &mut [], |p, _| p.reconnect())
+    }
+
+    fn preflight(&mut self) -> Preflight {
+        self.inner.preflight()
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        let req = Request::Read { addr, len: data.len() };
+        self.run(req, data, |p, data| p.read_into(addr, data))
+    }
+
+    fn read_ranges(&mut self, ranges: &[(usize, usize)]) -> Result<Vec<Vec<u8>>> {
+        let reqs = ranges.iter().map(|&(addr, len)| Request::Read { addr, len })
+                                .collect::<Vec<_>>();
+        reqs.iter().try_for_each(|req| self.before(req))?;
+        match self.inner.read_ranges(ranges) {
+            Ok(mut result) => {
+                for (req, data) in reqs.iter().zip(&mut result) {
+                    self.after(req, Ok(()), data)?;
+                }
+                Ok(result)
+            }
+            Err(e) => {
+                for req in &reqs {
+                    self.layers.iter_mut().rev().for_each(|l| l.on_error(req, &e));
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        self.run(Request::Write { addr, data }, &mut [], |p, _| p.write(addr, data))
+    }
+
+    fn write_masked(&mut self, addr: usize, data: &[u8], mask: &[u8]) -> Result<()> {
+        self.run(Request::WriteMasked { addr, data, mask }, &mut [],
+                 |p, _| p.write_masked(addr, data, mask))
+    }
+
+    fn write_ranges(&mut self, writes: &[(usize, &[u8])]) -> Result<()> {
+        let reqs = writes.iter().map(|&(addr, data)| Request::Write { addr, data })
+                                .collect::<Vec<_>>();
+        reqs.iter().try_for_each(|req| self.before(req))?;
+        match self.inner.write_ranges(writes) {
+            Ok(()) => {
+                for req in &reqs {
+                    self.after(req, Ok(()), &mut [])?;
+                }
+                Ok(())
+            }
+            Err(e) => {
+                for req in &reqs {
+                    self.layers.iter_mut().rev().for_each(|l| l.on_error(req, &e));
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn write_read(&mut self, addr: usize, data: &[u8], read_addr: usize,
+                  result: &mut [u8]) -> Result<()> {
+        let write = Request::Write { addr, data };
+        let read = Request::Read { addr: read_addr, len: result.len() };
+        self.before(&write)?;
+        self.before(&read)?;
+        match self.inner.write_read(addr, data, read_addr, result) {
+            Ok(()) => {
+                self.after(&write, Ok(()), &mut [])?;
+                self.after(&read, Ok(()), result)
+            }
+            Err(e) => {
+                for req in &[write, read] {
+                    self.layers.iter_mut().rev().for_each(|l| l.on_error(req, &e));
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn write_bit(&mut self, addr: usize, bit: u8, value: bool) -> Result<()> {
+        check_bit(bit)?;
+        let (data, mask) = ([(value as u8) << bit], [1 << bit]);
+        self.run(Request::WriteMasked { addr, data: &data, mask: &mask }, &mut [],
+                 |p, _| p.write_bit(addr, bit, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakePlc, call_overrides};
+
+    #[test]
+    fn forwards_overrides() {
+        let mut proto = Layered::new(FakePlc::new(4));
+        call_overrides(&mut proto);
+        // read_bit is left to the default, so that layers see a read
+        assert_eq!(proto.inner().calls(), ["read_into", "write_bit", "write_masked",
+                                           "read_ranges", "write_ranges", "write_read"]);
+    }
+}
@@ -22,18 +22,268 @@
 // *****************************************************************************
 
 pub mod ads;
+pub mod audit;
+pub mod breaker;
+pub mod cancel;
+pub mod coalesce;
+pub mod dump;
+pub mod events;
+pub mod failover;
+pub mod fins;
+pub mod force;
+pub mod http;
+pub mod keepalive;
+pub mod layer;
 pub mod modbus;
+pub mod pils;
+pub mod pool;
+pub mod replay;
+pub mod retry;
+pub mod shared;
+pub mod slmp;
+pub mod socks;
+pub mod stats;
+pub mod tap;
+pub mod throttle;
+pub mod tunnel;
+pub mod verify;
 #[cfg(feature = "tango_client")]
 pub mod tango;
+#[cfg(feature = "tracing")]
+pub mod traced;
 
-use std::time::Duration;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
 
-use crate::Result;
+use crate::{Error, Result};
 
 pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
 pub const READ_TIMEOUT: Duration = Duration::from_secs(1);
 pub const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
 
+/// Timeouts for the network operations of a protocol, defaulting to the
+/// constants above.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub connect: Duration,
+    pub read: Duration,
+    pub write: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self { connect: CONNECT_TIMEOUT, read: READ_TIMEOUT, write: WRITE_TIMEOUT }
+    }
+}
+
+/// Interval in which repeated identical errors are summarized.
+pub const ERROR_REPEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Error logging that suppresses repetitions of the same message, logging
+/// only a summary with the count every `ERROR_REPEAT_INTERVAL`.
+#[derive(Default)]
+pub(crate) struct ErrorLog {
+    last: Option<(String, Instant, usize)>,
+}
+
+impl ErrorLog {
+    pub fn report(&mut self, msg: String) {
+        if let Some((last, since, count)) = &mut self.last {
+            if *last == msg {
+                *count += 1;
+                if since.elapsed() >= ERROR_REPEAT_INTERVAL {
+                    log::error!("{} (repeated {} times)", msg, count);
+                    *since = Instant::now();
+                    *count = 0;
+                }
+                return;
+            }
+        }
+        self.flush();
+        log::error!("{}", msg);
+        self.last = Some((msg, Instant::now(), 0));
+    }
+
+    /// Forget the last error (e.g. after a successful reconnect), logging
+    /// the number of suppressed repetitions if necessary.
+    pub fn flush(&mut self) {
+        if let Some((last, _, count)) = self.last.take() {
+            if count > 0 {
+                log::error!("{} (repeated {} times)", last, count);
+            }
+        }
+    }
+}
+
+/// Split the query part of an address (`key=value&flag&...`) into pairs.
+pub(crate) fn parse_query(query: Option<&str>) -> Vec<(&str, &str)> {
+    query.into_iter()
+         .flat_map(|q| q.split('&'))
+         .filter(|kv| !kv.is_empty())
+         .map(|kv| {
+             let mut parts = kv.splitn(2, '=');
+             (parts.next().unwrap(), parts.next().unwrap_or(""))
+         })
+         .collect()
+}
+
+/// Find the largest request size between `min` and `max` (in multiples of
+/// `step`) for which `probe` succeeds, by binary search.  If not even `min`
+/// works, it is returned anyway as the safest choice.
+pub(crate) fn probe_request_size(min: usize, max: usize, step: usize,
+                                 mut probe: impl FnMut(usize) -> bool) -> usize {
+    if probe(max) {
+        return max;
+    }
+    if !probe(min) {
+        log::warn!("probing request size failed, using minimum of {}", min);
+        return min;
+    }
+    let (mut good, mut bad) = (min / step, max / step);
+    while bad - good > 1 {
+        let mid = (good + bad) / 2;
+        if probe(mid * step) {
+            good = mid;
+        } else {
+            bad = mid;
+        }
+    }
+    log::info!("using maximum request size of {}", good * step);
+    good * step
+}
+
+/// Report of the connection checks done by `Protocol::preflight`.
+///
+/// Checks are done in order, and stop at the first failed one, since the
+/// following checks would not give useful results.
+#[derive(Debug, Default)]
+pub struct Preflight {
+    pub checks: Vec<(String, Result<()>)>,
+}
+
+impl Preflight {
+    /// Record the result of a check, returning whether it succeeded.
+    pub fn check(&mut self, name: impl Into<String>, result: Result<()>) -> bool {
+        let ok = result.is_ok();
+        self.checks.push((name.into(), result));
+        ok
+    }
+
+    /// Check if all checks have succeeded.
+    pub fn is_ok(&self) -> bool {
+        self.checks.iter().all(|(_, result)| result.is_ok())
+    }
+
+    /// Check that the host name resolves and the TCP port accepts connections
+    /// within the given timeout.
+    pub fn tcp(&mut self, host: &str, port: u16, timeout: Duration) -> bool {
+        let addr = (host, port).to_socket_addrs().and_then(|mut addrs| addrs.next().ok_or_else(
+            || IoError::new(ErrorKind::NotFound, "host name has no addresses")));
+        let addr = match addr {
+            Ok(addr) => addr,
+            Err(e) => return self.check(format!("resolve host {}", host), Err(e.into())),
+        };
+        self.check(format!("resolve host {}", host), Ok(())) &&
+            self.check(format!("TCP port {} reachable", port),
+                       TcpStream::connect_timeout(&addr, timeout).map(drop)
+                                                              .map_err(Into::into))
+    }
+
+    /// Check that the protocol connects and the start of the image can be read.
+    pub fn connect_and_read<P: Protocol + ?Sized>(&mut self, proto: &mut P) -> bool {
+        self.check("connect", proto.connect()) &&
+            self.check("read from image", proto.read(0, 2).map(drop))
+    }
+}
+
+impl fmt::Display for Preflight {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, result) in &self.checks {
+            match result {
+                Ok(()) => writeln!(f, "[ ok ] {}", name)?,
+                Err(e) => writeln!(f, "[FAIL] {}: {}", name, e)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Group ranges that are at most `max_gap` bytes apart into spans, returned
+/// as (start, length, indices of the ranges in the span).
+pub(crate) fn merge_ranges(ranges: &[(usize, usize)],
+                           max_gap: usize) -> Vec<(usize, usize, Vec<usize>)> {
+    let mut order = (0..ranges.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| ranges[i].0);
+    let mut spans: Vec<(usize, usize, Vec<usize>)> = Vec::new();
+    for i in order {
+        let (addr, length) = ranges[i];
+        match spans.last_mut() {
+            Some((start, len, indices)) if addr <= *start + *len + max_gap => {
+                *len = (*len).max(addr + length - *start);
+                indices.push(i);
+            }
+            _ => spans.push((addr, length, vec![i])),
+        }
+    }
+    spans
+}
+
+/// Read ranges by merging those that are at most `max_gap` bytes apart into
+/// a single read, for backends where transferring the gaps is cheaper than
+/// another round-trip.
+pub(crate) fn read_merged<P: Protocol + ?Sized>(proto: &mut P, ranges: &[(usize, usize)],
+                                                max_gap: usize) -> Result<Vec<Vec<u8>>> {
+    let mut result = vec![Vec::new(); ranges.len()];
+    for (start, length, indices) in merge_ranges(ranges, max_gap) {
+        let data = proto.read(start, length)?;
+        for i in indices {
+            let (addr, length) = ranges[i];
+            result[i] = data[addr - start..addr - start + length].to_vec();
+        }
+    }
+    Ok(result)
+}
+
+/// Check a read or write request against the length policy of all backends:
+/// empty requests and requests extending past `size` (the end of the image
+/// that the backend can address) are rejected, never sent or truncated.
+pub(crate) fn check_length(addr: usize, len: usize, size: usize) -> Result<()> {
+    if len == 0 || addr.checked_add(len).map_or(true, |end| end > size) {
+        return Err(Error::InvalidLength(addr, len));
+    }
+    Ok(())
+}
+
+/// Check the bit number of a bit access.
+pub(crate) fn check_bit(bit: u8) -> Result<()> {
+    if bit >= 8 {
+        return Err(Error::InvalidBit(bit));
+    }
+    Ok(())
+}
+
+/// Check that the data and mask of a masked write have the same length.
+pub(crate) fn check_mask(data: &[u8], mask: &[u8]) -> Result<()> {
+    if data.len() != mask.len() {
+        return Err(Error::MaskLength(data.len(), mask.len()));
+    }
+    Ok(())
+}
+
+/// Write only the bits set in `mask` by reading, modifying and writing back
+/// the current contents.
+pub(crate) fn write_masked_rmw<P: Protocol + ?Sized>(proto: &mut P, addr: usize, data: &[u8],
+                                                     mask: &[u8]) -> Result<()> {
+    check_mask(data, mask)?;
+    let mut current = proto.read(addr, data.len())?;
+    for ((cur, new), mask) in current.iter_mut().zip(data).zip(mask) {
+        *cur = *cur & !mask | new & mask;
+    }
+    proto.write(addr, &current)
+}
+
 pub trait Protocol {
     fn connect(&mut self) -> Result<()>;
     fn disconnect(&mut self);
@@ -41,7 +291,19 @@ pub trait Protocol {
         self.connect()
     }
 
+    /// Read `data.len()` bytes at `addr`.
+    ///
+    /// Requests of zero bytes, and requests extending past the end of the
+    /// image that the backend can address, fail with `Error::InvalidLength`.
+    /// Backends transfer exactly the requested bytes; if the PLC returns a
+    /// different amount, that is an error as well.
+    ///
+    /// Erroring is the only policy; too long requests are never clamped,
+    /// since the caller's buffer would be left partly unfilled.  Device slots
+    /// are not checked here, only the image bounds: backends don't know the
+    /// device table.
     fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()>;
+    /// Write `data` at `addr`, with the same length rules as `read_into`.
     fn write(&mut self, addr: usize, data: &[u8]) -> Result<()>;
 
     fn read(&mut self, addr: usize, length: usize) -> Result<Vec<u8>> {
@@ -50,6 +312,238 @@ pub trait Protocol {
         Ok(vec)
     }
 
-    fn get_offsets() -> &'static [usize];
+    /// Read a single bit (0 to 7) of the byte at `addr`.
+    fn read_bit(&mut self, addr: usize, bit: u8) -> Result<bool> {
+        check_bit(bit)?;
+        Ok(self.read(addr, 1)?[0] & (1 << bit) != 0)
+    }
+
+    /// Write a single bit (0 to 7) of the byte at `addr`, leaving the other
+    /// bits unchanged.
+    fn write_bit(&mut self, addr: usize, bit: u8, value: bool) -> Result<()> {
+        check_bit(bit)?;
+        self.write_masked(addr, &[(value as u8) << bit], &[1 << bit])
+    }
+
+    /// Write only the bits of `data` that are set in `mask`, leaving all
+    /// other bits unchanged.  `data` and `mask` must have the same length,
+    /// otherwise `Error::MaskLength` is returned.
+    ///
+    /// Unless the backend can do this natively, it is done by reading and
+    /// writing back, which is not atomic with respect to the PLC.
+    fn write_masked(&mut self, addr: usize, data: &[u8], mask: &[u8]) -> Result<()> {
+        write_masked_rmw(self, addr, data, mask)
+    }
+
+    /// Read several (address, length) ranges.  Backends can override this
+    /// to need fewer round-trips than reading each range on its own.
+    fn read_ranges(&mut self, ranges: &[(usize, usize)]) -> Result<Vec<Vec<u8>>> {
+        ranges.iter().map(|&(addr, length)| self.read(addr, length)).collect()
+    }
+
+    /// Write several (address, data) ranges.  Backends can override this
+    /// to need fewer round-trips than writing each range on its own.
+    fn write_ranges(&mut self, writes: &[(usize, &[u8])]) -> Result<()> {
+        writes.iter().try_for_each(|&(addr, data)| self.write(addr, data))
+    }
+
+    /// Write data and then read back another (or the same) range, e.g. a
+    /// command and its acknowledgement.  Backends can override this to do
+    /// both in one round-trip.
+    fn write_read(&mut self, addr: usize, data: &[u8], read_addr: usize,
+                  result: &mut [u8]) -> Result<()> {
+        self.write(addr, data)?;
+        self.read_into(read_addr, result)
+    }
+
+    /// Read several (address, length) ranges, with a separate result for
+    /// each range, so that one unreadable range doesn't fail all others.
+    ///
+    /// The ranges are read together using `read_ranges` first, and only
+    /// if that fails one by one.
+    fn read_multi(&mut self, ranges: &[(usize, usize)]) -> Vec<Result<Vec<u8>>> {
+        match self.read_ranges(ranges) {
+            Ok(data) => data.into_iter().map(Ok).collect(),
+            Err(_) => ranges.iter().map(|&(addr, length)| self.read(addr, length)).collect(),
+        }
+    }
+
+    /// Check the connection step by step, to diagnose setup problems.
+    fn preflight(&mut self) -> Preflight {
+        let mut report = Preflight::default();
+        report.connect_and_read(self);
+        report
+    }
+
+    fn get_offsets(&self) -> &'static [usize];
     fn set_offset(&mut self, offset: usize);
 }
+
+impl<P: Protocol + ?Sized> Protocol for Box<P> {
+    fn connect(&mut self) -> Result<()> {
+        (**self).connect()
+    }
+
+    fn disconnect(&mut self) {
+        (**self).disconnect()
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        (**self).reconnect()
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        (**self).read_into(addr, data)
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        (**self).write(addr, data)
+    }
+
+    fn read(&mut self, addr: usize, length: usize) -> Result<Vec<u8>> {
+        (**self).read(addr, length)
+    }
+
+    fn read_bit(&mut self, addr: usize, bit: u8) -> Result<bool> {
+        (**self).read_bit(addr, bit)
+    }
+
+    fn write_bit(&mut self, addr: usize, bit: u8, value: bool) -> Result<()> {
+        (**self).write_bit(addr, bit, value)
+    }
+
+    fn write_masked(&mut self, addr: usize, data: &[u8], mask: &[u8]) -> Result<()> {
+        (**self).write_masked(addr, data, mask)
+    }
+
+    fn read_ranges(&mut self, ranges: &[(usize, usize)]) -> Result<Vec<Vec<u8>>> {
+        (**self).read_ranges(ranges)
+    }
+
+    fn write_ranges(&mut self, writes: &[(usize, &[u8])]) -> Result<()> {
+        (**self).write_ranges(writes)
+    }
+
+    fn write_read(&mut self, addr: usize, data: &[u8], read_addr: usize,
+                  result: &mut [u8]) -> Result<()> {
+        (**self).write_read(addr, data, read_addr, result)
+    }
+
+    fn read_multi(&mut self, ranges: &[(usize, usize)]) -> Vec<Result<Vec<u8>>> {
+        (**self).read_multi(ranges)
+    }
+
+    fn preflight(&mut self) -> Preflight {
+        (**self).preflight()
+    }
+
+    fn get_offsets(&self) -> &'static [usize] {
+        (**self).get_offsets()
+    }
+
+    fn set_offset(&mut self, offset: usize) {
+        (**self).set_offset(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakePlc;
+
+    #[test]
+    fn merge_ranges_groups_by_gap() {
+        let ranges = [(10, 2), (0, 4), (6, 2), (30, 1)];
+        assert_eq!(merge_ranges(&ranges, 2), vec![(0, 12, vec![1, 2, 0]), (30, 1, vec![3])]);
+        assert_eq!(merge_ranges(&ranges, 0), vec![(0, 4, vec![1]), (6, 2, vec![2]),
+                                                  (10, 2, vec![0]), (30, 1, vec![3])]);
+    }
+
+    #[test]
+    fn merge_ranges_overlapping() {
+        assert_eq!(merge_ranges(&[(0, 10), (2, 2), (8, 4)], 0), vec![(0, 12, vec![0, 1, 2])]);
+        assert!(merge_ranges(&[], 4).is_empty());
+    }
+
+    #[test]
+    fn read_merged_splits_result() {
+        let mut plc = FakePlc::new(16);
+        plc.set(0, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        let result = read_merged(&mut plc, &[(6, 2), (1, 2)], 4).unwrap();
+        assert_eq!(result, vec![vec![7, 8], vec![2, 3]]);
+    }
+
+    #[test]
+    fn probe_request_size_search() {
+        assert_eq!(probe_request_size(2, 256, 2, |n| n <= 100), 100);
+        assert_eq!(probe_request_size(2, 256, 2, |n| n <= 101), 100);
+        assert_eq!(probe_request_size(2, 256, 2, |_| true), 256);
+        assert_eq!(probe_request_size(2, 256, 2, |_| false), 2);
+    }
+
+    #[test]
+    fn check_length_policy() {
+        assert!(check_length(0, 4, 4).is_ok());
+        assert!(matches!(check_length(0, 0, 4), Err(Error::InvalidLength(0, 0))));
+        assert!(matches!(check_length(2, 3, 4), Err(Error::InvalidLength(2, 3))));
+        assert!(matches!(check_length(usize::MAX, 2, usize::MAX),
+                         Err(Error::InvalidLength(..))));
+
+        let mut plc = FakePlc::new(4);
+        assert!(matches!(plc.read(0, 0), Err(Error::InvalidLength(0, 0))));
+        assert!(matches!(plc.write(3, &[1, 2]), Err(Error::InvalidLength(3, 2))));
+        assert!(plc.writes().is_empty());
+    }
+
+    #[test]
+    fn write_masked_rmw_keeps_other_bits() {
+        let mut plc = FakePlc::new(4);
+        plc.set(1, &[0xF0, 0x0F]);
+        write_masked_rmw(&mut plc, 1, &[0xFF, 0x00], &[0x0F, 0x0F]).unwrap();
+        assert_eq!(plc.get(0, 4), &[0, 0xFF, 0x00, 0]);
+        assert_eq!(plc.writes(), &[(1, vec![0xFF, 0x00])]);
+        assert!(matches!(write_masked_rmw(&mut plc, 1, &[0xFF], &[0x0F, 0x0F]),
+                         Err(Error::MaskLength(1, 2))));
+    }
+
+    /// Only implements the required methods, to test the default ones.
+    struct Plain(FakePlc);
+
+    impl Protocol for Plain {
+        fn get_offsets(&self) -> &'static [usize] {
+            &[0]
+        }
+
+        fn set_offset(&mut self, _: usize) { }
+
+        fn connect(&mut self) -> Result<()> {
+            self.0.connect()
+        }
+
+        fn disconnect(&mut self) {
+            self.0.disconnect()
+        }
+
+        fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+            self.0.read_into(addr, data)
+        }
+
+        fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+            self.0.write(addr, data)
+        }
+    }
+
+    #[test]
+    fn write_bit_default() {
+        let mut plc = Plain(FakePlc::new(2));
+        plc.0.set(1, &[0x81]);
+        plc.write_bit(1, 3, true).unwrap();
+        plc.write_bit(1, 0, false).unwrap();
+        assert_eq!(plc.0.get(1, 1), &[0x88]);
+        assert_eq!(plc.0.calls(), ["read_into", "write", "read_into", "write"]);
+        assert!(plc.read_bit(1, 7).unwrap());
+        assert!(!plc.read_bit(1, 0).unwrap());
+        assert!(matches!(plc.read_bit(1, 8), Err(Error::InvalidBit(8))));
+        assert!(matches!(plc.write_bit(1, 8, true), Err(Error::InvalidBit(8))));
+    }
+}
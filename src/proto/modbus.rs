@@ -22,135 +22,865 @@
 // *****************************************************************************
 
 use std::convert::TryInto;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
 
 use crate::{Error, Result};
-use crate::proto::{CONNECT_TIMEOUT, Protocol, READ_TIMEOUT, WRITE_TIMEOUT};
+use crate::proto::{ErrorLog, Preflight, Protocol, Timeouts, check_length, check_mask,
+                   parse_query, probe_request_size, read_merged, write_masked_rmw};
+use crate::proto::cancel::{Cancel, CancelToken};
+use crate::proto::socks::{Socks5Proxy, Socks5Relay};
 
-use modbus::{Client, tcp::Config};
+use modbus::{Client, Coil, tcp::Config};
 use regex::Regex;
 use once_cell::sync::Lazy;
 
 static MB_ADDR_RE: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(r"modbus://(.+?)(?::(\d+))?(?:/(\d+)?)?$")
+    Regex::new(r"modbus(\+tls)?://(.+?)(?::(\d+))?(?:/(\d+)?)?(?:\?(.*))?$")
         .expect("invalid regex")
 });
-const MB_ADDR_FMT: &str = "modbus://host[:port]/slave";
+const MB_ADDR_FMT: &str = "modbus[+tls]://host[:port]/slave[?options]";
 
 const MB_PORT: u16 = 502;
+const MB_TLS_PORT: u16 = 802;
+
+/// Maximum number of bytes in a read request (125 registers).
+const MB_MAX_READ: usize = 250;
+
+/// Maximum number of bytes in a write request (123 registers).
+const MB_MAX_WRITE: usize = 246;
+
+/// Maximum number of bytes written by a read/write request (121 registers).
+const MB_MAX_RW_WRITE: usize = 242;
+
+/// Maximum gap between ranges that are merged into one read.
+const MB_MERGE_GAP: usize = 32;
+
+type MbResult<T> = std::result::Result<T, modbus::Error>;
+
+fn invalid(msg: &str) -> modbus::Error {
+    modbus::Error::InvalidData(modbus::Reason::Custom(msg.into()))
+}
+
+/// The Modbus data area used to access the image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Area {
+    /// Holding registers, FC 3 and 16.
+    Registers,
+    /// Input registers for reading (FC 4), holding registers for writing.
+    InputRegisters,
+    /// Coils, FC 1 and 15, packed 8 per byte.
+    Coils,
+    /// Discrete inputs for reading (FC 2), coils for writing (FC 15).
+    Inputs,
+}
+
+impl Area {
+    fn is_registers(self) -> bool {
+        matches!(self, Area::Registers | Area::InputRegisters)
+    }
+}
+
+/// Certificates for a Modbus/TCP Security connection, given as address query
+/// parameters (`ca=`, `cert=` and `key=`, all PEM file names).
+#[derive(Debug, Default, Clone)]
+pub struct TlsOptions {
+    pub ca: Option<String>,
+    pub cert: Option<String>,
+    pub key: Option<String>,
+}
+
+trait Stream: Read + Write + Send {}
+
+impl<S: Read + Write + Send> Stream for S {}
+
+enum Conn {
+    Plain(modbus::Transport),
+    /// Our own client, for TLS and for function codes the `modbus` crate
+    /// doesn't implement.
+    Mbap(Mbap<Box<dyn Stream>>),
+}
+
+impl Conn {
+    fn read_registers(&mut self, area: Area, addr: u16, count: u16) -> MbResult<Vec<u16>> {
+        match self {
+            Conn::Plain(t) => if area == Area::InputRegisters {
+                t.read_input_registers(addr, count)
+            } else {
+                t.read_holding_registers(addr, count)
+            },
+            Conn::Mbap(t) => {
+                let function = if area == Area::InputRegisters { 0x04 } else { 0x03 };
+                t.read_registers(function, addr, count)
+            }
+        }
+    }
+
+    fn write_single_register(&mut self, addr: u16, value: u16) -> MbResult<()> {
+        match self {
+            Conn::Plain(t) => t.write_single_register(addr, value),
+            Conn::Mbap(t) => t.write_single_register(addr, value),
+        }
+    }
+
+    fn write_multiple_registers(&mut self, addr: u16, values: &[u16]) -> MbResult<()> {
+        match self {
+            Conn::Plain(t) => t.write_multiple_registers(addr, values),
+            Conn::Mbap(t) => t.write_multiple_registers(addr, values),
+        }
+    }
+
+    fn read_bits(&mut self, area: Area, addr: u16, count: u16) -> MbResult<Vec<bool>> {
+        let bits = match self {
+            Conn::Plain(t) => if area == Area::Inputs {
+                t.read_discrete_inputs(addr, count)?
+            } else {
+                t.read_coils(addr, count)?
+            },
+            Conn::Mbap(t) => return t.read_bits(if area == Area::Inputs { 0x02 } else { 0x01 },
+                                                addr, count),
+        };
+        Ok(bits.into_iter().map(|c| c == Coil::On).collect())
+    }
+
+    fn write_multiple_coils(&mut self, addr: u16, values: &[bool]) -> MbResult<()> {
+        match self {
+            Conn::Plain(t) => {
+                let coils = values.iter().map(|&v| if v { Coil::On } else { Coil::Off })
+                                  .collect::<Vec<_>>();
+                t.write_multiple_coils(addr, &coils)
+            }
+            Conn::Mbap(t) => t.write_multiple_coils(addr, values),
+        }
+    }
+
+    /// Mask write of a register (FC 22), which the `modbus` crate doesn't
+    /// implement.  Returns None if the connection can't send it.
+    fn mask_write_register(&mut self, addr: u16, and: u16, or: u16) -> Option<MbResult<()>> {
+        match self {
+            Conn::Plain(_) => None,
+            Conn::Mbap(t) => Some(t.mask_write_register(addr, and, or)),
+        }
+    }
+
+    /// Write and read registers in one request (FC 23), which the `modbus`
+    /// crate doesn't implement.  Returns None if the connection can't send it.
+    fn read_write_registers(&mut self, read_addr: u16, count: u16, write_addr: u16,
+                            values: &[u16]) -> Option<MbResult<Vec<u16>>> {
+        match self {
+            Conn::Plain(_) => None,
+            Conn::Mbap(t) => Some(t.read_write_registers(read_addr, count, write_addr, values)),
+        }
+    }
+
+    /// Read `data.len()` bytes from the given area.  `addr` is in units of the
+    /// area, i.e. registers or bits.
+    fn read_bytes(&mut self, area: Area, addr: u16, data: &mut [u8]) -> MbResult<()> {
+        if area.is_registers() {
+            let regs = self.read_registers(area, addr, (data.len() / 2) as u16)?;
+            for (i, reg) in regs.into_iter().enumerate() {
+                data[2*i] = reg as u8;
+                data[2*i + 1] = (reg >> 8) as u8;
+            }
+        } else {
+            let bits = self.read_bits(area, addr, (data.len() * 8) as u16)?;
+            if bits.len() < data.len() * 8 {
+                return Err(invalid("unexpected reply size"));
+            }
+            for (byte, bits) in data.iter_mut().zip(bits.chunks(8)) {
+                *byte = bits.iter().rev().fold(0, |acc, &bit| acc << 1 | bit as u8);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write `data` to the given area, with FC 6 instead of FC 16 for a single
+    /// register if `single` is set.
+    fn write_bytes(&mut self, area: Area, addr: u16, data: &[u8], single: bool) -> MbResult<()> {
+        if area.is_registers() {
+            let mut regs = vec![0; data.len() / 2];
+            for (i, reg) in regs.iter_mut().enumerate() {
+                *reg = data[2*i] as u16 | (data[2*i + 1] as u16) << 8;
+            }
+            if single && regs.len() == 1 {
+                self.write_single_register(addr, regs[0])
+            } else {
+                self.write_multiple_registers(addr, &regs)
+            }
+        } else {
+            let bits = data.iter().flat_map(|&byte| (0..8).map(move |i| byte & (1 << i) != 0))
+                                  .collect::<Vec<_>>();
+            self.write_multiple_coils(addr, &bits)
+        }
+    }
+}
+
+/// Minimal Modbus/TCP (MBAP) client over an arbitrary stream, used where the
+/// `modbus` crate's own transport can't be used.
+struct Mbap<S> {
+    stream: S,
+    uid: u8,
+    tid: u16,
+}
+
+impl<S: Read + Write> Mbap<S> {
+    fn new(stream: S, uid: u8) -> Self {
+        Self { stream, uid, tid: 0 }
+    }
+
+    fn request(&mut self, pdu: &[u8]) -> MbResult<Vec<u8>> {
+        self.tid = self.tid.wrapping_add(1);
+        let mut frame = Vec::with_capacity(7 + pdu.len());
+        frame.extend_from_slice(&self.tid.to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+        frame.push(self.uid);
+        frame.extend_from_slice(pdu);
+        self.stream.write_all(&frame).map_err(modbus::Error::Io)?;
+
+        let mut header = [0; 7];
+        self.stream.read_exact(&mut header).map_err(modbus::Error::Io)?;
+        let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        if length < 2 {
+            return Err(modbus::Error::InvalidResponse);
+        }
+        let mut reply = vec![0; length - 1];
+        self.stream.read_exact(&mut reply).map_err(modbus::Error::Io)?;
+        if header[0..2] != self.tid.to_be_bytes() || header[6] != self.uid {
+            return Err(modbus::Error::InvalidResponse);
+        }
+        if reply[0] == pdu[0] | 0x80 {
+            return Err(invalid(&format!("exception code {}", reply.get(1).unwrap_or(&0))));
+        }
+        if reply[0] != pdu[0] {
+            return Err(modbus::Error::InvalidResponse);
+        }
+        Ok(reply)
+    }
+
+    fn read_registers(&mut self, function: u8, addr: u16, count: u16) -> MbResult<Vec<u16>> {
+        let [a0, a1] = addr.to_be_bytes();
+        let [c0, c1] = count.to_be_bytes();
+        let reply = self.request(&[function, a0, a1, c0, c1])?;
+        if reply.len() != 2 + 2 * count as usize || reply[1] as usize != 2 * count as usize {
+            return Err(invalid("unexpected reply size"));
+        }
+        Ok(reply[2..].chunks(2).map(|w| u16::from_be_bytes([w[0], w[1]])).collect())
+    }
+
+    fn write_multiple_registers(&mut self, addr: u16, values: &[u16]) -> MbResult<()> {
+        let mut pdu = vec![0x10];
+        pdu.extend_from_slice(&addr.to_be_bytes());
+        pdu.extend_from_slice(&(values.len() as u16).to_be_bytes());
+        pdu.push((2 * values.len()) as u8);
+        for value in values {
+            pdu.extend_from_slice(&value.to_be_bytes());
+        }
+        self.request(&pdu).map(drop)
+    }
+
+    fn write_single_register(&mut self, addr: u16, value: u16) -> MbResult<()> {
+        let [a0, a1] = addr.to_be_bytes();
+        let [v0, v1] = value.to_be_bytes();
+        self.request(&[0x06, a0, a1, v0, v1]).map(drop)
+    }
+
+    fn read_write_registers(&mut self, read_addr: u16, count: u16, write_addr: u16,
+                            values: &[u16]) -> MbResult<Vec<u16>> {
+        let mut pdu = vec![0x17];
+        pdu.extend_from_slice(&read_addr.to_be_bytes());
+        pdu.extend_from_slice(&count.to_be_bytes());
+        pdu.extend_from_slice(&write_addr.to_be_bytes());
+        pdu.extend_from_slice(&(values.len() as u16).to_be_bytes());
+        pdu.push((2 * values.len()) as u8);
+        for value in values {
+            pdu.extend_from_slice(&value.to_be_bytes());
+        }
+        let reply = self.request(&pdu)?;
+        if reply.len() != 2 + 2 * count as usize || reply[1] as usize != 2 * count as usize {
+            return Err(invalid("unexpected reply size"));
+        }
+        Ok(reply[2..].chunks(2).map(|w| u16::from_be_bytes([w[0], w[1]])).collect())
+    }
+
+    fn mask_write_register(&mut self, addr: u16, and: u16, or: u16) -> MbResult<()> {
+        let mut pdu = vec![0x16];
+        pdu.extend_from_slice(&addr.to_be_bytes());
+        pdu.extend_from_slice(&and.to_be_bytes());
+        pdu.extend_from_slice(&or.to_be_bytes());
+        self.request(&pdu).map(drop)
+    }
+
+    fn read_bits(&mut self, function: u8, addr: u16, count: u16) -> MbResult<Vec<bool>> {
+        let [a0, a1] = addr.to_be_bytes();
+        let [c0, c1] = count.to_be_bytes();
+        let reply = self.request(&[function, a0, a1, c0, c1])?;
+        let nbytes = (count as usize + 7) / 8;
+        if reply.len() != 2 + nbytes || reply[1] as usize != nbytes {
+            return Err(invalid("unexpected reply size"));
+        }
+        Ok((0..count as usize).map(|i| reply[2 + i/8] & (1 << (i % 8)) != 0).collect())
+    }
+
+    fn write_multiple_coils(&mut self, addr: u16, values: &[bool]) -> MbResult<()> {
+        let mut pdu = vec![0x0F];
+        pdu.extend_from_slice(&addr.to_be_bytes());
+        pdu.extend_from_slice(&(values.len() as u16).to_be_bytes());
+        pdu.push(((values.len() + 7) / 8) as u8);
+        for bits in values.chunks(8) {
+            pdu.push(bits.iter().rev().fold(0, |acc, &bit| acc << 1 | bit as u8));
+        }
+        self.request(&pdu).map(drop)
+    }
+}
 
 pub struct ModbusProto {
     host: String,
     config: Config,
-    client: Option<modbus::Transport>,
+    timeouts: Timeouts,
+    cancel: Cancel,
+    tls: Option<TlsOptions>,
+    socks: Option<Socks5Proxy>,
+    relay: Option<Socks5Relay>,
+    client: Option<Conn>,
+    area: Area,
+    errors: ErrorLog,
     offset: usize,
+    max_read: usize,
+    tune: bool,
+    // use FC 22 for write_masked, false once the slave has rejected it
+    mask_write: bool,
+    swap_bytes: bool,
+    swap_words: bool,
+    // write single registers with FC 6 instead of FC 16
+    single_writes: bool,
+    // use FC 23 for write_read, false once the slave has rejected it
+    read_write: bool,
 }
 
 impl ModbusProto {
+    /// Create the protocol from an address like `modbus://host:502/1?options`.
+    ///
+    /// The `fc22` and `fc23` options select our own Modbus/TCP client instead
+    /// of the `modbus` crate's, in order to do masked writes (FC 22) and
+    /// write/read requests (FC 23) in a single request.  Without them, masked
+    /// writes are done by read-modify-write, except over TLS or with a cancel
+    /// token, which always use our own client.
     pub fn new(addr: &str) -> Result<Self> {
         let err0 = || Error::InvalidAddress(MB_ADDR_FMT);
         let err1 = |_| Error::InvalidAddress(MB_ADDR_FMT);
         let caps = MB_ADDR_RE.captures(addr).ok_or_else(err0)?;
-        let host = caps[1].into();
-        let port = if let Some(port) = caps.get(2) {
+        let secure = caps.get(1).is_some();
+        let host = caps[2].into();
+        let port = if let Some(port) = caps.get(3) {
             port.as_str().parse().map_err(err1)?
+        } else if secure {
+            MB_TLS_PORT
         } else {
             MB_PORT
         };
-        let slave = if let Some(slave) = caps.get(3) {
+        let slave = if let Some(slave) = caps.get(4) {
             slave.as_str().parse().map_err(err1)?
         } else {
             0
         };
+        let mut tls = TlsOptions::default();
+        let mut max_read = MB_MAX_READ;
+        let mut tune = false;
+        let mut socks = None;
+        let mut area = Area::Registers;
+        let (mut swap_bytes, mut swap_words) = (false, false);
+        let mut single_writes = false;
+        let mut mask_write = secure;
+        let mut read_write = false;
+        for (key, value) in parse_query(caps.get(5).map(|m| m.as_str())) {
+            match key {
+                "area" => area = match value {
+                    "registers" => Area::Registers,
+                    "input-registers" => Area::InputRegisters,
+                    "coils" => Area::Coils,
+                    "inputs" => Area::Inputs,
+                    _ => return Err(Error::InvalidAddress(
+                        "modbus://...?area=registers|input-registers|coils|inputs")),
+                },
+                "write" => single_writes = match value {
+                    "multiple" => false,
+                    "single" => true,
+                    _ => return Err(Error::InvalidAddress("modbus://...?write=multiple|single")),
+                },
+                "swap" => match value {
+                    "bytes" => swap_bytes = true,
+                    "words" => swap_words = true,
+                    "both" => { swap_bytes = true; swap_words = true; }
+                    _ => return Err(Error::InvalidAddress("modbus://...?swap=bytes|words|both")),
+                },
+                "fc22" => mask_write = true,
+                "fc23" => read_write = true,
+                "socks5" => socks = Some(Socks5Proxy::new(value)?),
+                "mtu" if value == "auto" => tune = true,
+                "mtu" => max_read = value.parse::<usize>().map_err(err1)?
+                                         .min(MB_MAX_READ).max(2) & !1,
+                "ca" if secure => tls.ca = Some(value.into()),
+                "cert" if secure => tls.cert = Some(value.into()),
+                "key" if secure => tls.key = Some(value.into()),
+                _ => return Err(err0()),
+            }
+        }
+        if tls.cert.is_some() != tls.key.is_some() {
+            return Err(Error::InvalidAddress("modbus+tls://...?cert=file&key=file"));
+        }
+        // timeouts are filled in when connecting
         let config = Config {
             tcp_port: port,
             modbus_uid: slave,
-            tcp_connect_timeout: Some(CONNECT_TIMEOUT),
-            tcp_read_timeout: Some(READ_TIMEOUT),
-            tcp_write_timeout: Some(WRITE_TIMEOUT),
+            tcp_connect_timeout: None,
+            tcp_read_timeout: None,
+            tcp_write_timeout: None,
         };
 
-        Ok(Self { host, config, offset: 0, client: None })
+        Ok(Self { host, config, timeouts: Timeouts::default(), cancel: Cancel::default(),
+                  offset: 0, client: None, area, max_read, tune,
+                  tls: if secure { Some(tls) } else { None }, socks, relay: None,
+                  mask_write, swap_bytes, swap_words, single_writes, read_write,
+                  errors: ErrorLog::default() })
+    }
+
+    /// Use the given timeouts instead of the default ones.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
     }
 
+    /// Abort pending requests when the given token is cancelled.  This uses
+    /// our own client, since the `modbus` crate's connection can't be shut
+    /// down from outside.
+    pub fn with_cancel(mut self, token: CancelToken) -> Self {
+        self.cancel = Cancel::new(token);
+        self
+    }
+
+    /// Connect if necessary, or if a cancel has shut down the connection.
+    fn ensure_connected(&mut self) -> Result<()> {
+        self.cancel.check()?;
+        if self.client.is_none() || self.cancel.is_shut_down() {
+            self.reconnect()?;
+        }
+        Ok(())
+    }
+
+    /// Convert a byte address into registers or bits, depending on the area.
     fn convert_addr(&self, addr: usize) -> Result<u16> {
-        ((self.offset + addr) / 2)
+        let addr = self.offset + addr;
+        (if self.area.is_registers() { addr / 2 } else { addr * 8 })
             .try_into()
-            .map_err(|_| modbus::Error::InvalidData(
-                modbus::Reason::Custom("Address too big".into())).into())
+            .map_err(|_| invalid("Address too big").into())
     }
-}
 
-impl Protocol for ModbusProto {
-    fn get_offsets() -> &'static [usize] {
-        &[0, 0x6000, 0x8000]
+    fn open(&mut self) -> Result<Conn> {
+        let config = Config {
+            tcp_connect_timeout: Some(self.timeouts.connect),
+            tcp_read_timeout: Some(self.timeouts.read),
+            tcp_write_timeout: Some(self.timeouts.write),
+            ..self.config
+        };
+        if self.tls.is_some() || self.mask_write || self.read_write || self.cancel.is_active() {
+            let stream = self.connect_tcp()?;
+            self.cancel.watch(&stream)?;
+            let stream: Box<dyn Stream> = match &self.tls {
+                Some(tls) => self.connect_tls(tls, stream)?,
+                None => Box::new(stream),
+            };
+            Ok(Conn::Mbap(Mbap::new(stream, self.config.modbus_uid)))
+        } else if let Some(proxy) = &self.socks {
+            if self.relay.is_none() {
+                self.relay = Some(Socks5Relay::start(proxy.clone(), &self.host,
+                                                     self.config.tcp_port, self.timeouts)?);
+            }
+            let config = Config { tcp_port: self.relay.as_ref().unwrap().port(), ..config };
+            Ok(Conn::Plain(modbus::Transport::new_with_cfg("127.0.0.1", config)?))
+        } else {
+            Ok(Conn::Plain(modbus::Transport::new_with_cfg(&self.host, config)?))
+        }
     }
 
-    fn set_offset(&mut self, offset: usize) {
-        self.offset = offset;
+    /// Try to do a masked write using FC 22, returning false if it is not
+    /// supported by the connection or the slave.
+    fn try_mask_write(&mut self, addr: usize, data: &[u8], mask: &[u8]) -> Result<bool> {
+        self.ensure_connected()?;
+        let start = self.offset + addr;
+        let first = self.convert_addr(addr)?;
+        let nregs = (start + data.len() + 1) / 2 - start / 2;
+        let mut and = vec![0xFFFF_u16; nregs];
+        let mut or = vec![0_u16; nregs];
+        for (i, (&byte, &bits)) in data.iter().zip(mask).enumerate() {
+            // the low byte of each register comes first in the image
+            let reg = (start + i) / 2 - start / 2;
+            let shift = 8 * ((start + i) % 2);
+            and[reg] &= !((bits as u16) << shift);
+            or[reg] |= ((byte & bits) as u16) << shift;
+        }
+        let client = self.client.as_mut().unwrap();
+        for (i, (&and, &or)) in and.iter().zip(&or).enumerate() {
+            if and == 0xFFFF {
+                continue;
+            }
+            match client.mask_write_register(first + i as u16, and, or) {
+                None => return Ok(false),
+                Some(Ok(())) => (),
+                Some(Err(modbus::Error::Io(ioe))) => {
+                    self.disconnect();
+                    self.errors.report(format!("during Modbus write: {}", ioe));
+                    return Err(Error::Wrapped(Box::new(modbus::Error::Io(ioe).into()), "write"));
+                }
+                Some(Err(e)) => {
+                    log::info!("mask write not supported ({}), using read-modify-write", e);
+                    self.mask_write = false;
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
     }
 
-    fn connect(&mut self) -> Result<()> {
-        let client = modbus::Transport::new_with_cfg(&self.host, self.config)?;
+    /// Try to write and read using FC 23, returning false if it is not
+    /// supported by the slave.
+    fn try_read_write(&mut self, addr: usize, data: &[u8], read_addr: usize,
+                      result: &mut [u8]) -> Result<bool> {
+        self.ensure_connected()?;
+        let write_addr = self.convert_addr(addr)?;
+        let read_addr = self.convert_addr(read_addr)?;
+        let values = data.chunks(2).map(|w| w[0] as u16 | (w[1] as u16) << 8)
+                                   .collect::<Vec<_>>();
+        let count = (result.len() / 2) as u16;
+        let client = self.client.as_mut().unwrap();
+        match client.read_write_registers(read_addr, count, write_addr, &values) {
+            None => Ok(false),
+            Some(Ok(regs)) => {
+                for (i, reg) in regs.into_iter().enumerate() {
+                    result[2*i] = reg as u8;
+                    result[2*i + 1] = (reg >> 8) as u8;
+                }
+                Ok(true)
+            }
+            Some(Err(modbus::Error::Io(ioe))) => {
+                self.disconnect();
+                self.errors.report(format!("during Modbus write/read: {}", ioe));
+                Err(Error::Wrapped(Box::new(modbus::Error::Io(ioe).into()), "write"))
+            }
+            Some(Err(e)) => {
+                log::info!("read/write request not supported ({}), using separate requests", e);
+                self.read_write = false;
+                Ok(false)
+            }
+        }
+    }
 
-        self.client = Some(client);
+    fn probe_read(&mut self, len: usize) -> bool {
+        let addr = match self.convert_addr(0) {
+            Ok(addr) => addr,
+            Err(_) => return false,
+        };
+        let area = self.area;
+        match self.client.as_mut().unwrap().read_bytes(area, addr, &mut vec![0; len]) {
+            Ok(_) => true,
+            Err(modbus::Error::Io(_)) => {
+                // some gateways just drop the connection on too large requests
+                if let Ok(client) = self.open() {
+                    self.client = Some(client);
+                }
+                false
+            }
+            Err(_) => false,
+        }
+    }
 
-        log::info!("connected to {}", self.host);
-        Ok(())
+    /// Open a TCP connection for our own client.
+    fn connect_tcp(&self) -> Result<TcpStream> {
+        let stream = if let Some(proxy) = &self.socks {
+            proxy.connect(&self.host, self.config.tcp_port, self.timeouts)?
+        } else {
+            let addr = (self.host.as_str(), self.config.tcp_port).to_socket_addrs()?.next()
+                .ok_or_else(|| Error::InvalidAddress(MB_ADDR_FMT))?;
+            TcpStream::connect_timeout(&addr, self.timeouts.connect)?
+        };
+        stream.set_read_timeout(Some(self.timeouts.read))?;
+        stream.set_write_timeout(Some(self.timeouts.write))?;
+        Ok(stream)
     }
 
-    fn disconnect(&mut self) {
-        self.client = None;
+    #[cfg(feature = "tls")]
+    fn connect_tls(&self, tls: &TlsOptions, stream: TcpStream) -> Result<Box<dyn Stream>> {
+        let mut builder = native_tls::TlsConnector::builder();
+        if let Some(ca) = &tls.ca {
+            builder.add_root_certificate(native_tls::Certificate::from_pem(&std::fs::read(ca)?)?);
+        }
+        if let (Some(cert), Some(key)) = (&tls.cert, &tls.key) {
+            builder.identity(native_tls::Identity::from_pkcs8(&std::fs::read(cert)?,
+                                                              &std::fs::read(key)?)?);
+        }
+        let connector = builder.build()?;
+
+        let stream = connector.connect(&self.host, stream).map_err(|e| match e {
+            native_tls::HandshakeError::Failure(e) => Error::TLS(e),
+            native_tls::HandshakeError::WouldBlock(_) =>
+                Error::Unsupported("non-blocking TLS handshake"),
+        })?;
+        Ok(Box::new(stream))
     }
 
-    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
-        if self.client.is_none() {
-            self.reconnect()?;
+    #[cfg(not(feature = "tls"))]
+    fn connect_tls(&self, _: &TlsOptions, _: TcpStream) -> Result<Box<dyn Stream>> {
+        Err(Error::Unsupported("Modbus/TCP Security needs the \"tls\" feature"))
+    }
+
+    /// Check if register contents need to be reordered.
+    fn reordered(&self) -> bool {
+        self.area.is_registers() && (self.swap_bytes || self.swap_words)
+    }
+
+    /// Size of the image in bytes, register and coil addresses have 16 bits.
+    fn image_size(&self) -> usize {
+        let size = if self.area.is_registers() { 2 * 0x10000 } else { 0x10000 / 8 };
+        size - self.offset.min(size)
+    }
+
+    /// Extend a byte range to whole registers, or register pairs if words are
+    /// swapped.
+    fn aligned(&self, addr: usize, len: usize) -> (usize, usize) {
+        let align = match (self.area.is_registers(), self.swap_words) {
+            (false, _) => return (addr, addr + len),
+            (true, false) => 2,
+            (true, true) => 4,
+        };
+        let start = (self.offset + addr) / align * align;
+        let end = (self.offset + addr + len + align - 1) / align * align;
+        (start - self.offset, end - self.offset)
+    }
+
+    /// Convert between the gateway's and the PLC's order of the image bytes.
+    /// This is its own inverse, so it is used for reading and writing.
+    fn reorder(&self, data: &mut [u8]) {
+        if self.swap_bytes {
+            data.chunks_mut(2).for_each(|w| w.reverse());
         }
+        if self.swap_words {
+            for pair in data.chunks_mut(4) {
+                let (lo, hi) = pair.split_at_mut(2);
+                lo.swap_with_slice(hi);
+            }
+        }
+    }
+
+    fn read_raw(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        self.ensure_connected()?;
         let mut addr = self.convert_addr(addr)?;
+        let area = self.area;
         let client = self.client.as_mut().unwrap();
-        // TODO split requests if too large data is requested
         let mut length = data.len();
         let mut offset = 0;
         while length > 0 {
-            let plen = length.min(250);
-            match client.read_holding_registers(addr, (plen / 2) as u16) {
-                Ok(regs) => {
-                    for (i, reg) in regs.into_iter().enumerate() {
-                        data[offset + 2*i] = reg as u8;
-                        data[offset + 2*i + 1] = (reg >> 8) as u8;
-                    }
-                }
+            let plen = length.min(self.max_read);
+            match client.read_bytes(area, addr, &mut data[offset..offset + plen]) {
+                Ok(()) => (),
                 Err(modbus::Error::Io(ioe)) => {
                     self.disconnect();
-                    log::error!("during Modbus read: {}", ioe);
+                    self.errors.report(format!("during Modbus read: {}", ioe));
                     return Err(Error::Wrapped(Box::new(modbus::Error::Io(ioe).into()), "read"));
                 }
                 Err(e) => return Err(e.into())
             }
             length -= plen;
             offset += plen;
-            addr += (plen / 2) as u16;
+            // wraps only after the last chunk at the end of the address space
+            let step = if area.is_registers() { plen / 2 } else { plen * 8 };
+            addr = addr.wrapping_add(step as u16);
         }
         Ok(())
     }
 
+    fn write_raw(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        self.ensure_connected()?;
+        let mut addr = self.convert_addr(addr)?;
+        let (area, single) = (self.area, self.single_writes);
+        let client = self.client.as_mut().unwrap();
+        // a reduced request size applies to writes as well
+        let max_write = self.max_read.min(MB_MAX_WRITE);
+        for (i, chunk) in data.chunks(max_write).enumerate() {
+            if let Err(e) = client.write_bytes(area, addr, chunk, single) {
+                if i > 0 {
+                    log::warn!("Modbus write failed after {} of {} bytes",
+                               i * max_write, data.len());
+                }
+                return Err(if let modbus::Error::Io(ioe) = e {
+                    self.disconnect();
+                    self.errors.report(format!("during Modbus write: {}", ioe));
+                    Error::Wrapped(Box::new(modbus::Error::Io(ioe).into()), "write")
+                } else {
+                    e.into()
+                });
+            }
+            let step = if area.is_registers() { chunk.len() / 2 } else { chunk.len() * 8 };
+            addr = addr.wrapping_add(step as u16);
+        }
+        Ok(())
+    }
+}
+
+impl Protocol for ModbusProto {
+    fn get_offsets(&self) -> &'static [usize] {
+        &[0, 0x6000, 0x8000]
+    }
+
+    fn set_offset(&mut self, offset: usize) {
+        self.offset = offset;
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        self.cancel.check()?;
+        self.client = Some(self.open()?);
+        if self.tune {
+            self.max_read = probe_request_size(2, MB_MAX_READ, 2, |n| self.probe_read(n));
+        }
+
+        self.errors.flush();
+        log::info!("connected to {}", self.host);
+        Ok(())
+    }
+
+    fn preflight(&mut self) -> Preflight {
+        let mut report = Preflight::default();
+        if self.socks.is_some() || report.tcp(&self.host, self.config.tcp_port,
+                                                  self.timeouts.connect) {
+            report.connect_and_read(self);
+        }
+        report
+    }
+
+    fn disconnect(&mut self) {
+        self.client = None;
+        self.cancel.release();
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        check_length(addr, data.len(), self.image_size())?;
+        let (start, end) = self.aligned(addr, data.len());
+        if !self.reordered() && (start, end) == (addr, addr + data.len()) {
+            return self.read_raw(addr, data);
+        }
+        // registers can only be read as a whole
+        let mut buf = vec![0; end - start];
+        self.read_raw(start, &mut buf)?;
+        if self.reordered() {
+            self.reorder(&mut buf);
+        }
+        data.copy_from_slice(&buf[addr - start..addr - start + data.len()]);
+        Ok(())
+    }
+
+    fn read_ranges(&mut self, ranges: &[(usize, usize)]) -> Result<Vec<Vec<u8>>> {
+        read_merged(self, ranges, MB_MERGE_GAP)
+    }
+
+    fn write_masked(&mut self, addr: usize, data: &[u8], mask: &[u8]) -> Result<()> {
+        check_mask(data, mask)?;
+        check_length(addr, data.len(), self.image_size())?;
+        if self.area.is_registers() && self.mask_write && !self.reordered() &&
+            self.try_mask_write(addr, data, mask)?
+        {
+            return Ok(());
+        }
+        // registers that were already mask-written are unaffected by this
+        write_masked_rmw(self, addr, data, mask)
+    }
+
+    fn write_read(&mut self, addr: usize, data: &[u8], read_addr: usize,
+                  result: &mut [u8]) -> Result<()> {
+        check_length(addr, data.len(), self.image_size())?;
+        check_length(read_addr, result.len(), self.image_size())?;
+        // only whole registers can be transferred directly
+        let aligned = [self.offset + addr, data.len(), self.offset + read_addr, result.len()]
+            .iter().all(|n| n % 2 == 0);
+        if self.read_write && self.area == Area::Registers && !self.reordered() && aligned &&
+            !data.is_empty() && data.len() <= MB_MAX_RW_WRITE && result.len() <= self.max_read &&
+            self.try_read_write(addr, data, read_addr, result)?
+        {
+            return Ok(());
+        }
+        self.write(addr, data)?;
+        self.read_into(read_addr, result)
+    }
+
     fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
-        if self.client.is_none() {
-            self.reconnect()?;
+        check_length(addr, data.len(), self.image_size())?;
+        let (start, end) = self.aligned(addr, data.len());
+        let exact = (start, end) == (addr, addr + data.len());
+        if !self.reordered() && exact {
+            return self.write_raw(addr, data);
         }
-        let addr = self.convert_addr(addr)?;
-        let client = self.client.as_mut().unwrap();
-        let mut regs = vec![0; data.len() / 2];
-        for (i, reg) in regs.iter_mut().enumerate() {
-            *reg = data[2*i] as u16 | (data[2*i + 1] as u16) << 8;
-        }
-        client.write_multiple_registers(addr, &regs)
-              .map_err(|e| if let modbus::Error::Io(ioe) = e {
-                  self.disconnect();
-                  log::error!("during Modbus write: {}", ioe);
-                  Error::Wrapped(Box::new(modbus::Error::Io(ioe).into()), "write")
-              } else {
-                  e.into()
-              })
+        let mut buf = if exact {
+            data.to_vec()
+        } else {
+            // complete partially written registers with current contents
+            let mut buf = self.read(start, end - start)?;
+            buf[addr - start..addr - start + data.len()].copy_from_slice(data);
+            buf
+        };
+        if self.reordered() {
+            self.reorder(&mut buf);
+        }
+        self.write_raw(start, &buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reorder() {
+        let data = [1, 2, 3, 4, 5, 6, 7, 8];
+        for (swap, expected) in [("bytes", [2, 1, 4, 3, 6, 5, 8, 7]),
+                                 ("words", [3, 4, 1, 2, 7, 8, 5, 6]),
+                                 ("both", [4, 3, 2, 1, 8, 7, 6, 5])].iter() {
+            let proto = ModbusProto::new(&format!("modbus://host/0?swap={}", swap)).unwrap();
+            let mut buf = data;
+            proto.reorder(&mut buf);
+            assert_eq!(&buf, expected, "swap={}", swap);
+            // it is its own inverse
+            proto.reorder(&mut buf);
+            assert_eq!(buf, data, "swap={}", swap);
+        }
+    }
+
+    #[test]
+    fn aligned_swapped_words() {
+        let mut proto = ModbusProto::new("modbus://host/0?swap=words").unwrap();
+        assert_eq!(proto.aligned(4, 4), (4, 8));
+        assert_eq!(proto.aligned(2, 4), (0, 8));
+        proto.set_offset(0x6000);
+        assert_eq!(proto.aligned(5, 1), (4, 8));
+    }
+
+    #[test]
+    fn aligned_registers() {
+        let proto = ModbusProto::new("modbus://host/0").unwrap();
+        assert_eq!(proto.aligned(2, 4), (2, 6));
+        assert_eq!(proto.aligned(1, 2), (0, 4));
+        assert_eq!(proto.aligned(3, 1), (2, 4));
+
+        let proto = ModbusProto::new("modbus://host/0?area=coils&swap=words").unwrap();
+        assert_eq!(proto.aligned(1, 3), (1, 4));
+    }
+
+    #[test]
+    fn image_size() {
+        let mut proto = ModbusProto::new("modbus://host/0").unwrap();
+        assert_eq!(proto.image_size(), 0x20000);
+        proto.set_offset(0x8000);
+        assert_eq!(proto.image_size(), 0x18000);
+        let mut proto = ModbusProto::new("modbus://host/0?area=coils").unwrap();
+        assert_eq!(proto.image_size(), 0x2000);
+        proto.set_offset(0x8000);
+        assert_eq!(proto.image_size(), 0);
     }
 }
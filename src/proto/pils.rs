@@ -0,0 +1,282 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! A minimal TCP protocol for embedded devices that can't run a Modbus or ADS
+//! stack, mapping 1:1 onto the `Protocol` trait.
+//!
+//! Requests consist of a command byte (1 = read, 2 = write), the image address
+//! (u32) and the data length (u32), followed by the data for writes.
+//!
+//! Replies consist of a status byte (0 = ok, else error) and a length (u32),
+//! followed by that many bytes: the data for reads, nothing for writes, and an
+//! error message if the status is nonzero.
+//!
+//! All integers are big-endian.  See `examples/pils_server.rs` for a server.
+//!
+//! For unreliable links (e.g. via serial-to-TCP converters), frames can be
+//! protected (`?crc` option): the command byte has the `PROTECTED` bit set,
+//! a sequence number (u16) follows the command or status byte, and a CRC-16
+//! (Modbus variant) over the whole frame is appended.  Damaged or mismatched
+//! replies are retransmitted after reconnecting, which is safe since all
+//! requests are idempotent.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+use crate::{Error, Result};
+use crate::proto::{ErrorLog, Preflight, Protocol, Timeouts, check_length, parse_query};
+use crate::proto::cancel::{Cancel, CancelToken};
+
+use regex::Regex;
+use once_cell::sync::Lazy;
+
+static PILS_ADDR_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"pils://(.+?):(\d+)/?(?:\?(.*))?$")
+        .expect("invalid regex")
+});
+const PILS_ADDR_FMT: &str = "pils://host:port[?crc]";
+
+pub const CMD_READ: u8 = 1;
+pub const CMD_WRITE: u8 = 2;
+/// Flag in the command byte for protected frames.
+pub const PROTECTED: u8 = 0x80;
+
+pub const STATUS_OK: u8 = 0;
+pub const STATUS_ERROR: u8 = 1;
+
+/// Number of retransmissions of protected frames.
+const RETRIES: usize = 3;
+
+/// Compute the CRC-16 (Modbus variant) of the given data.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFF_u16;
+    for &byte in data {
+        crc ^= byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xA001 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+pub struct PilsProto {
+    host: String,
+    port: u16,
+    protected: bool,
+    seq: u16,
+    stream: Option<TcpStream>,
+    timeouts: Timeouts,
+    cancel: Cancel,
+    errors: ErrorLog,
+    offset: usize,
+}
+
+impl PilsProto {
+    pub fn new(addr: &str) -> Result<Self> {
+        let err0 = || Error::InvalidAddress(PILS_ADDR_FMT);
+        let err1 = |_| Error::InvalidAddress(PILS_ADDR_FMT);
+        let caps = PILS_ADDR_RE.captures(addr).ok_or_else(err0)?;
+        let host = caps[1].into();
+        let port = caps[2].parse().map_err(err1)?;
+        let mut protected = false;
+        for (key, _) in parse_query(caps.get(3).map(|m| m.as_str())) {
+            match key {
+                "crc" => protected = true,
+                _ => return Err(err0()),
+            }
+        }
+
+        Ok(Self { host, port, protected, seq: 0, stream: None, offset: 0,
+                  timeouts: Timeouts::default(), cancel: Cancel::default(),
+                  errors: ErrorLog::default() })
+    }
+
+    /// Use the given timeouts instead of the default ones.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Abort pending requests when the given token is cancelled.
+    pub fn with_cancel(mut self, token: CancelToken) -> Self {
+        self.cancel = Cancel::new(token);
+        self
+    }
+
+    /// Size of the image in bytes, addresses are 32 bits on the wire.
+    fn image_size(&self) -> usize {
+        (u32::MAX as usize).saturating_sub(self.offset)
+    }
+
+    fn transact(&mut self, command: u8, addr: usize, len: usize,
+                payload: &[u8]) -> Result<Vec<u8>> {
+        let addr = self.offset + addr;
+        if addr > u32::MAX as usize || len > u32::MAX as usize {
+            return Err(Error::PILS("address or length too big".into()));
+        }
+        let mut retries = 0;
+        loop {
+            self.cancel.check()?;
+            if self.stream.is_none() || self.cancel.is_shut_down() {
+                self.reconnect()?;
+            }
+            match self.exchange(command, addr as u32, len as u32, payload) {
+                Ok((STATUS_OK, reply)) => return Ok(reply),
+                Ok((_, msg)) => return Err(Error::PILS(String::from_utf8_lossy(&msg).into())),
+                // with protected frames, damaged requests or replies are
+                // expected and the connection is probably out of sync
+                Err(ioe) if self.protected && retries < RETRIES => {
+                    log::warn!("PILS-direct request failed ({}), retransmitting", ioe);
+                    self.disconnect();
+                    retries += 1;
+                }
+                Err(ioe) => {
+                    self.disconnect();
+                    self.errors.report(format!("during PILS-direct request: {}", ioe));
+                    return Err(Error::Wrapped(Box::new(ioe.into()),
+                                              if command == CMD_READ { "read" } else { "write" }));
+                }
+            }
+        }
+    }
+
+    /// Send one request and receive the reply, as (status, data).
+    fn exchange(&mut self, command: u8, addr: u32, len: u32,
+                payload: &[u8]) -> io::Result<(u8, Vec<u8>)> {
+        self.seq = self.seq.wrapping_add(1);
+        let seq = self.seq.to_be_bytes();
+        let stream = self.stream.as_mut().unwrap();
+
+        let mut frame = Vec::with_capacity(13 + payload.len());
+        if self.protected {
+            frame.push(command | PROTECTED);
+            frame.extend_from_slice(&seq);
+        } else {
+            frame.push(command);
+        }
+        frame.extend_from_slice(&addr.to_be_bytes());
+        frame.extend_from_slice(&len.to_be_bytes());
+        frame.extend_from_slice(payload);
+        if self.protected {
+            let crc = crc16(&frame);
+            frame.extend_from_slice(&crc.to_be_bytes());
+        }
+        stream.write_all(&frame)?;
+
+        // status, sequence number if protected, and length
+        let mut header = vec![0; if self.protected { 7 } else { 5 }];
+        stream.read_exact(&mut header)?;
+        let n = header.len();
+        let length = u32::from_be_bytes([header[n-4], header[n-3], header[n-2], header[n-1]]);
+        if !self.protected {
+            let mut reply = vec![0; length as usize];
+            stream.read_exact(&mut reply)?;
+            return Ok((header[0], reply));
+        }
+        let damaged = |msg| io::Error::new(ErrorKind::InvalidData, msg);
+        // a damaged length could make us wait for lots of data
+        if length > len.max(0xFFFF) {
+            return Err(damaged("invalid reply length"));
+        }
+        let mut reply = vec![0; length as usize + 2];
+        stream.read_exact(&mut reply)?;
+        let crc = u16::from_be_bytes([reply[length as usize], reply[length as usize + 1]]);
+        reply.truncate(length as usize);
+        header.extend_from_slice(&reply);
+        if crc16(&header) != crc {
+            return Err(damaged("CRC mismatch in reply"));
+        }
+        if header[1..3] != seq {
+            return Err(damaged("sequence number mismatch in reply"));
+        }
+        Ok((header[0], reply))
+    }
+}
+
+impl Protocol for PilsProto {
+    fn get_offsets(&self) -> &'static [usize] {
+        &[0]
+    }
+
+    fn set_offset(&mut self, offset: usize) {
+        self.offset = offset;
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        self.cancel.check()?;
+        let addr = (self.host.as_str(), self.port).to_socket_addrs()?.next()
+            .ok_or_else(|| Error::InvalidAddress(PILS_ADDR_FMT))?;
+        let stream = TcpStream::connect_timeout(&addr, self.timeouts.connect)?;
+        stream.set_read_timeout(Some(self.timeouts.read))?;
+        stream.set_write_timeout(Some(self.timeouts.write))?;
+        stream.set_nodelay(true)?;
+        self.cancel.watch(&stream)?;
+        self.stream = Some(stream);
+
+        self.errors.flush();
+        log::info!("connected to {}:{}", self.host, self.port);
+        Ok(())
+    }
+
+    fn preflight(&mut self) -> Preflight {
+        let mut report = Preflight::default();
+        if report.tcp(&self.host, self.port, self.timeouts.connect) {
+            report.connect_and_read(self);
+        }
+        report
+    }
+
+    fn disconnect(&mut self) {
+        self.stream = None;
+        self.cancel.release();
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        check_length(addr, data.len(), self.image_size())?;
+        let reply = self.transact(CMD_READ, addr, data.len(), &[])?;
+        if reply.len() != data.len() {
+            // the stream is still in sync, since the reply was length-prefixed
+            return Err(Error::PILS("unexpected reply size".into()));
+        }
+        data.copy_from_slice(&reply);
+        Ok(())
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        check_length(addr, data.len(), self.image_size())?;
+        self.transact(CMD_WRITE, addr, data.len(), data)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc16_modbus() {
+        assert_eq!(crc16(b""), 0xFFFF);
+        assert_eq!(crc16(b"123456789"), 0x4B37);
+        assert_eq!(crc16(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x01]), 0x0A84);
+    }
+}
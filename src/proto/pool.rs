@@ -0,0 +1,169 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! A pool of parallel connections to the same PLC.
+
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{Error, Result};
+use crate::proto::{Preflight, Protocol};
+
+/// A set of connections to the same endpoint, with requests distributed
+/// over them.
+///
+/// Like `SharedProto`, the pool can be cloned and used from several threads;
+/// each request goes to the next connection that is not busy, so that up to
+/// `size` requests can run in parallel.
+pub struct Pool<P> {
+    conns: Arc<Vec<Mutex<P>>>,
+    next: Arc<AtomicUsize>,
+}
+
+impl<P> Clone for Pool<P> {
+    fn clone(&self) -> Self {
+        Self { conns: self.conns.clone(), next: self.next.clone() }
+    }
+}
+
+impl<P: Protocol> Pool<P> {
+    /// Create a pool of `size` connections, each created by calling `make`.
+    /// The connections are opened on first use.  `size` must be at least 1.
+    pub fn new(size: usize, mut make: impl FnMut() -> Result<P>) -> Result<Self> {
+        if size == 0 {
+            return Err(Error::Unsupported("pool without connections"));
+        }
+        let conns = (0..size).map(|_| make().map(Mutex::new)).collect::<Result<_>>()?;
+        Ok(Self { conns: Arc::new(conns), next: Arc::new(AtomicUsize::new(0)) })
+    }
+
+    /// The number of connections in the pool.
+    pub fn size(&self) -> usize {
+        self.conns.len()
+    }
+
+    /// Run an operation on the first idle connection, starting with the one
+    /// after the last used, or wait for one if all are busy.
+    fn run<T>(&self, op: impl FnOnce(&mut P) -> T) -> T {
+        let n = self.conns.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % n;
+        for i in 0..n {
+            match self.conns[(start + i) % n].try_lock() {
+                Ok(mut conn) => return op(&mut conn),
+                Err(TryLockError::Poisoned(e)) => return op(&mut e.into_inner()),
+                Err(TryLockError::WouldBlock) => (),
+            }
+        }
+        op(&mut lock(&self.conns[start]))
+    }
+
+    /// Run an operation on all connections.
+    fn run_all<T>(&self, mut op: impl FnMut(&mut P) -> T) -> Vec<T> {
+        self.conns.iter().map(|conn| op(&mut lock(conn))).collect()
+    }
+}
+
+fn lock<P>(conn: &Mutex<P>) -> MutexGuard<'_, P> {
+    // a panic during a request doesn't leave the protocol in an unusable state
+    conn.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl<P: Protocol> Protocol for Pool<P> {
+    fn get_offsets(&self) -> &'static [usize] {
+        lock(&self.conns[0]).get_offsets()
+    }
+
+    fn set_offset(&mut self, offset: usize) {
+        self.run_all(|p| p.set_offset(offset));
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        self.run_all(|p| p.connect()).into_iter().collect()
+    }
+
+    fn disconnect(&mut self) {
+        self.run_all(|p| p.disconnect());
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.run_all(|p| p.reconnect()).into_iter().collect()
+    }
+
+    fn preflight(&mut self) -> Preflight {
+        // all connections go to the same endpoint, checking one is enough
+        lock(&self.conns[0]).preflight()
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        self.run(|p| p.read_into(addr, data))
+    }
+
+    fn read_ranges(&mut self, ranges: &[(usize, usize)]) -> Result<Vec<Vec<u8>>> {
+        self.run(|p| p.read_ranges(ranges))
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        self.run(|p| p.write(addr, data))
+    }
+
+    fn write_masked(&mut self, addr: usize, data: &[u8], mask: &[u8]) -> Result<()> {
+        // requests on other connections can get between read and write
+        // if the backend does this by read-modify-write
+        self.run(|p| p.write_masked(addr, data, mask))
+    }
+
+    fn read_bit(&mut self, addr: usize, bit: u8) -> Result<bool> {
+        self.run(|p| p.read_bit(addr, bit))
+    }
+
+    fn write_bit(&mut self, addr: usize, bit: u8, value: bool) -> Result<()> {
+        self.run(|p| p.write_bit(addr, bit, value))
+    }
+
+    fn write_ranges(&mut self, writes: &[(usize, &[u8])]) -> Result<()> {
+        self.run(|p| p.write_ranges(writes))
+    }
+
+    fn write_read(&mut self, addr: usize, data: &[u8], read_addr: usize,
+                  result: &mut [u8]) -> Result<()> {
+        self.run(|p| p.write_read(addr, data, read_addr, result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakePlc, OVERRIDES, call_overrides};
+
+    #[test]
+    fn empty_pool() {
+        assert!(matches!(Pool::new(0, || Ok(FakePlc::new(4))), Err(Error::Unsupported(_))));
+    }
+
+    #[test]
+    fn forwards_overrides() {
+        let mut pool = Pool::new(1, || Ok(FakePlc::new(4))).unwrap();
+        call_overrides(&mut pool);
+        assert_eq!(lock(&pool.conns[0]).calls(), OVERRIDES);
+    }
+}
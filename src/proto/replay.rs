@@ -0,0 +1,302 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! Playback of recorded transaction logs.
+//!
+//! A log file starts with the magic bytes `ZAPFLOG1`, followed by records of:
+//!
+//! * timestamp in microseconds since the epoch (u64)
+//! * kind: 0 = read, 1 = write (u8)
+//! * status: 0 = ok, 1 = error (u8)
+//! * address as given to the protocol (u64)
+//! * data length (u32)
+//! * data: the bytes read or written, or the error message
+//!
+//! All integers are little-endian.
+//!
+//! Logs are written by the `Recorder` interceptor, see `proto::layer`.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{Error, Result};
+use crate::proto::{Protocol, check_length, check_mask};
+use crate::proto::layer::{Interceptor, Request};
+
+use regex::Regex;
+use once_cell::sync::Lazy;
+
+static REPLAY_ADDR_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"replay://(.+)$")
+        .expect("invalid regex")
+});
+const REPLAY_ADDR_FMT: &str = "replay://path/to/logfile";
+
+pub const LOG_MAGIC: &[u8; 8] = b"ZAPFLOG1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Read,
+    Write,
+}
+
+/// A single recorded transaction.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub time: SystemTime,
+    pub kind: Kind,
+    pub ok: bool,
+    pub addr: usize,
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    /// Read the next record, returning None at the end of the log.
+    pub fn read_from(mut r: impl Read) -> io::Result<Option<Self>> {
+        let mut head = [0; 22];
+        match r.read_exact(&mut head) {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let mut u64_at = [0; 8];
+        u64_at.copy_from_slice(&head[0..8]);
+        let micros = u64::from_le_bytes(u64_at);
+        u64_at.copy_from_slice(&head[10..18]);
+        let addr = u64::from_le_bytes(u64_at) as usize;
+        let len = u32::from_le_bytes([head[18], head[19], head[20], head[21]]) as usize;
+        let kind = match head[8] {
+            0 => Kind::Read,
+            1 => Kind::Write,
+            _ => return Err(io::Error::new(ErrorKind::InvalidData, "invalid record kind")),
+        };
+        let mut data = vec![0; len];
+        r.read_exact(&mut data)?;
+        Ok(Some(Self {
+            time: UNIX_EPOCH + Duration::from_micros(micros),
+            kind,
+            ok: head[9] == 0,
+            addr,
+            data,
+        }))
+    }
+
+    pub fn write_to(&self, mut w: impl Write) -> io::Result<()> {
+        let micros = self.time.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros();
+        let mut head = Vec::with_capacity(22 + self.data.len());
+        head.extend_from_slice(&(micros as u64).to_le_bytes());
+        head.push(if self.kind == Kind::Read { 0 } else { 1 });
+        head.push(if self.ok { 0 } else { 1 });
+        head.extend_from_slice(&(self.addr as u64).to_le_bytes());
+        head.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        head.extend_from_slice(&self.data);
+        w.write_all(&head)
+    }
+}
+
+/// Read a whole transaction log.
+pub fn read_log(path: &str) -> io::Result<Vec<Record>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut magic = [0; 8];
+    file.read_exact(&mut magic)?;
+    if &magic != LOG_MAGIC {
+        return Err(io::Error::new(ErrorKind::InvalidData, "not a zapf transaction log"));
+    }
+    let mut records = Vec::new();
+    while let Some(record) = Record::read_from(&mut file)? {
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// An interceptor that records all reads and writes to a log file.
+/// Masked writes are recorded as plain writes of the data.
+pub struct Recorder {
+    file: BufWriter<File>,
+}
+
+impl Recorder {
+    pub fn create(path: &str) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(LOG_MAGIC)?;
+        file.flush()?;
+        Ok(Self { file })
+    }
+
+    fn record(&mut self, req: &Request<'_>, ok: bool, data: &[u8]) {
+        let (kind, addr) = match *req {
+            Request::Connect => return,
+            Request::Read { addr, .. } => (Kind::Read, addr),
+            Request::Write { addr, .. } | Request::WriteMasked { addr, .. } => (Kind::Write, addr),
+        };
+        let record = Record { time: SystemTime::now(), kind, ok, addr, data: data.to_vec() };
+        // flush every record, so that nothing is lost if the program dies
+        if let Err(e) = record.write_to(&mut self.file).and_then(|_| self.file.flush()) {
+            log::warn!("could not write transaction log: {}", e);
+        }
+    }
+}
+
+impl Interceptor for Recorder {
+    fn after_response(&mut self, req: &Request<'_>, data: &mut [u8]) {
+        match *req {
+            Request::Write { data, .. } | Request::WriteMasked { data, .. } =>
+                self.record(req, true, data),
+            _ => self.record(req, true, data),
+        }
+    }
+
+    fn on_error(&mut self, req: &Request<'_>, error: &Error) {
+        self.record(req, false, error.to_string().as_bytes());
+    }
+}
+
+/// A protocol that answers reads from a recorded transaction log.
+///
+/// Reads are matched by address and length against the recorded reads, in
+/// order of recording; writes are accepted and checked against the log.
+pub struct ReplayProto {
+    path: String,
+    records: Vec<Record>,
+    pos: usize,
+}
+
+impl ReplayProto {
+    pub fn new(addr: &str) -> Result<Self> {
+        let caps = REPLAY_ADDR_RE.captures(addr)
+                                 .ok_or(Error::InvalidAddress(REPLAY_ADDR_FMT))?;
+        Ok(Self { path: caps[1].into(), records: Vec::new(), pos: 0 })
+    }
+
+    /// Find the next matching record at or after the current position,
+    /// wrapping around at the end of the log.
+    fn find(&mut self, kind: Kind, addr: usize, len: usize) -> Option<&Record> {
+        let n = self.records.len();
+        let found = (0..n).map(|i| (self.pos + i) % n).find(|&i| {
+            let rec = &self.records[i];
+            rec.kind == kind && rec.addr == addr && (!rec.ok || rec.data.len() == len)
+        })?;
+        self.pos = found + 1;
+        Some(&self.records[found])
+    }
+}
+
+impl Protocol for ReplayProto {
+    fn get_offsets(&self) -> &'static [usize] {
+        // all offsets any backend might have probed during recording
+        &[0, 0x6000, 0x8000]
+    }
+
+    fn set_offset(&mut self, _: usize) { }
+
+    fn connect(&mut self) -> Result<()> {
+        self.records = read_log(&self.path)?;
+        self.pos = 0;
+        log::info!("loaded {} records from {}", self.records.len(), self.path);
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        self.records.clear();
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        // the recorded backend would have rejected it as well
+        check_length(addr, data.len(), usize::MAX)?;
+        if self.records.is_empty() {
+            self.reconnect()?;
+        }
+        match self.find(Kind::Read, addr, data.len()) {
+            Some(rec) if rec.ok => {
+                data.copy_from_slice(&rec.data);
+                Ok(())
+            }
+            Some(rec) => Err(io::Error::new(ErrorKind::Other, format!(
+                "recorded error: {}", String::from_utf8_lossy(&rec.data))).into()),
+            None => Err(Error::Replay(format!(
+                "no recorded read of {} bytes at {}", data.len(), addr))),
+        }
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        check_length(addr, data.len(), usize::MAX)?;
+        if self.records.is_empty() {
+            self.reconnect()?;
+        }
+        match self.find(Kind::Write, addr, data.len()) {
+            Some(rec) if rec.ok && rec.data != data => {
+                log::warn!("write at {} differs from recording", addr);
+            }
+            Some(_) => (),
+            None => log::warn!("write at {} not in recording", addr),
+        }
+        Ok(())
+    }
+
+    fn write_masked(&mut self, addr: usize, data: &[u8], mask: &[u8]) -> Result<()> {
+        check_mask(data, mask)?;
+        // recorded as a plain write, there is no read to replay
+        self.write(addr, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_roundtrip() {
+        let records = [
+            Record { time: UNIX_EPOCH + Duration::from_micros(1_634_000_000_123_456),
+                     kind: Kind::Read, ok: true, addr: 0x8000, data: vec![1, 2, 3] },
+            Record { time: UNIX_EPOCH, kind: Kind::Write, ok: false, addr: 12,
+                     data: b"timed out".to_vec() },
+        ];
+        let mut buf = Vec::new();
+        for record in &records {
+            record.write_to(&mut buf).unwrap();
+        }
+        let mut r = &buf[..];
+        for record in &records {
+            let read = Record::read_from(&mut r).unwrap().unwrap();
+            assert_eq!(read.time, record.time);
+            assert_eq!(read.kind, record.kind);
+            assert_eq!(read.ok, record.ok);
+            assert_eq!(read.addr, record.addr);
+            assert_eq!(read.data, record.data);
+        }
+        assert!(Record::read_from(&mut r).unwrap().is_none());
+    }
+
+    #[test]
+    fn record_invalid() {
+        let mut buf = Vec::new();
+        Record { time: UNIX_EPOCH, kind: Kind::Read, ok: true, addr: 0, data: vec![0; 4] }
+            .write_to(&mut buf).unwrap();
+        // truncated data
+        assert!(Record::read_from(&buf[..buf.len() - 1]).is_err());
+        buf[8] = 2;
+        assert_eq!(Record::read_from(&buf[..]).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
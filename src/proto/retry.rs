@@ -0,0 +1,198 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! Retrying reads and writes on transient errors.
+//!
+//! Only connection errors (see `Error::is_connection_error`) are retried,
+//! errors reported by the PLC or the protocol are passed on immediately.
+
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::Result;
+use crate::proto::{Preflight, Protocol};
+
+/// How often and how long to wait before retrying.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt.
+    pub count: usize,
+    /// Delay before the first retry, doubled for each following one.
+    pub base_delay: Duration,
+    /// Upper limit for the delay.
+    pub max_delay: Duration,
+    /// Random variation of the delay, as a fraction between 0 and 1.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            count: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(2),
+            jitter: 0.2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the given retry (starting at 0), with `random` a value
+    /// between 0 and 1 used for the jitter.
+    pub fn delay(&self, retry: usize, random: f64) -> Duration {
+        let delay = self.base_delay.checked_mul(1 << retry.min(31))
+                                   .map_or(self.max_delay, |d| d.min(self.max_delay));
+        let jitter = self.jitter.max(0.).min(1.);
+        delay.mul_f64(1. + jitter * (2. * random - 1.))
+    }
+}
+
+pub struct Retrying<P> {
+    inner: P,
+    policy: RetryPolicy,
+    // state of the xorshift generator for the jitter
+    rng: u64,
+}
+
+impl<P: Protocol> Retrying<P> {
+    pub fn new(inner: P, policy: RetryPolicy) -> Self {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        Self { inner, policy, rng: seed as u64 | 1 }
+    }
+
+    fn random(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Run an operation, retrying it with reconnects on connection errors.
+    fn run<T>(&mut self, mut op: impl FnMut(&mut P) -> Result<T>) -> Result<T> {
+        let mut retry = 0;
+        loop {
+            match op(&mut self.inner) {
+                Err(e) if e.is_connection_error() && retry < self.policy.count => {
+                    let random = self.random();
+                    let delay = self.policy.delay(retry, random);
+                    log::warn!("{}, retrying in {:?}", e, delay);
+                    thread::sleep(delay);
+                    retry += 1;
+                    self.inner.disconnect();
+                    match self.inner.reconnect() {
+                        Ok(()) => (),
+                        Err(e) if e.is_connection_error() => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+impl<P> Retrying<P> {
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+}
+
+impl<P: Protocol> Protocol for Retrying<P> {
+    fn get_offsets(&self) -> &'static [usize] {
+        self.inner.get_offsets()
+    }
+
+    fn set_offset(&mut self, offset: usize) {
+        self.inner.set_offset(offset);
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        self.inner.connect()
+    }
+
+    fn disconnect(&mut self) {
+        self.inner.disconnect();
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.inner.reconnect()
+    }
+
+    fn preflight(&mut self) -> Preflight {
+        self.inner.preflight()
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        self.run(|p| p.read_into(addr, data))
+    }
+
+    fn read_ranges(&mut self, ranges: &[(usize, usize)]) -> Result<Vec<Vec<u8>>> {
+        self.run(|p| p.read_ranges(ranges))
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        self.run(|p| p.write(addr, data))
+    }
+
+    fn write_masked(&mut self, addr: usize, data: &[u8], mask: &[u8]) -> Result<()> {
+        self.run(|p| p.write_masked(addr, data, mask))
+    }
+
+    fn write_ranges(&mut self, writes: &[(usize, &[u8])]) -> Result<()> {
+        self.run(|p| p.write_ranges(writes))
+    }
+
+    fn write_read(&mut self, addr: usize, data: &[u8], read_addr: usize,
+                  result: &mut [u8]) -> Result<()> {
+        self.run(|p| p.write_read(addr, data, read_addr, result))
+    }
+
+    fn read_bit(&mut self, addr: usize, bit: u8) -> Result<bool> {
+        self.run(|p| p.read_bit(addr, bit))
+    }
+
+    fn write_bit(&mut self, addr: usize, bit: u8, value: bool) -> Result<()> {
+        self.run(|p| p.write_bit(addr, bit, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakePlc, OVERRIDES, call_overrides};
+
+    #[test]
+    fn forwards_overrides() {
+        let mut proto = Retrying::new(FakePlc::new(4), RetryPolicy::default());
+        call_overrides(&mut proto);
+        assert_eq!(proto.inner().calls(), OVERRIDES);
+    }
+}
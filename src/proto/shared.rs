@@ -0,0 +1,121 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! Sharing one connection between threads.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::Result;
+use crate::proto::{Preflight, Protocol};
+
+/// A handle to a protocol that can be cloned and sent to other threads.
+///
+/// All clones use the same connection, and each request is done while
+/// holding a lock, so that requests from different threads don't interleave.
+/// Compound operations like `write_masked` are forwarded as a whole, so they
+/// are atomic with respect to the other handles.
+pub struct SharedProto<P> {
+    inner: Arc<Mutex<P>>,
+}
+
+impl<P> Clone for SharedProto<P> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone() }
+    }
+}
+
+impl<P: Protocol> SharedProto<P> {
+    pub fn new(inner: P) -> Self {
+        Self { inner: Arc::new(Mutex::new(inner)) }
+    }
+
+    /// Lock the connection, to do several requests without others getting
+    /// in between.
+    pub fn lock(&self) -> MutexGuard<'_, P> {
+        // a panic during a request doesn't leave the protocol in an unusable state
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<P: Protocol> Protocol for SharedProto<P> {
+    fn get_offsets(&self) -> &'static [usize] {
+        self.lock().get_offsets()
+    }
+
+    fn set_offset(&mut self, offset: usize) {
+        self.lock().set_offset(offset)
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        self.lock().connect()
+    }
+
+    fn disconnect(&mut self) {
+        self.lock().disconnect()
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.lock().reconnect()
+    }
+
+    fn preflight(&mut self) -> Preflight {
+        self.lock().preflight()
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        self.lock().read_into(addr, data)
+    }
+
+    fn read_bit(&mut self, addr: usize, bit: u8) -> Result<bool> {
+        self.lock().read_bit(addr, bit)
+    }
+
+    fn read_ranges(&mut self, ranges: &[(usize, usize)]) -> Result<Vec<Vec<u8>>> {
+        self.lock().read_ranges(ranges)
+    }
+
+    fn read_multi(&mut self, ranges: &[(usize, usize)]) -> Vec<Result<Vec<u8>>> {
+        self.lock().read_multi(ranges)
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        self.lock().write(addr, data)
+    }
+
+    fn write_bit(&mut self, addr: usize, bit: u8, value: bool) -> Result<()> {
+        self.lock().write_bit(addr, bit, value)
+    }
+
+    fn write_masked(&mut self, addr: usize, data: &[u8], mask: &[u8]) -> Result<()> {
+        self.lock().write_masked(addr, data, mask)
+    }
+
+    fn write_ranges(&mut self, writes: &[(usize, &[u8])]) -> Result<()> {
+        self.lock().write_ranges(writes)
+    }
+
+    fn write_read(&mut self, addr: usize, data: &[u8], read_addr: usize,
+                  result: &mut [u8]) -> Result<()> {
+        self.lock().write_read(addr, data, read_addr, result)
+    }
+}
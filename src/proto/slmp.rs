@@ -0,0 +1,263 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! Access to Mitsubishi PLCs via the MC protocol (SLMP) using binary 3E
+//! frames.  The PILS image is located in word devices, starting at the device
+//! given in the address.
+
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+use crate::{Error, Result};
+use crate::proto::{ErrorLog, Preflight, Protocol, Timeouts, check_length, parse_query,
+                   probe_request_size};
+use crate::proto::cancel::{Cancel, CancelToken};
+
+use regex::Regex;
+use once_cell::sync::Lazy;
+
+static SLMP_ADDR_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"slmp://(.+?):(\d+)/(D|W|R|ZR)([0-9A-Fa-f]+)(?:\?(.*))?$")
+        .expect("invalid regex")
+});
+const SLMP_ADDR_FMT: &str = "slmp://host:port/device (D, W, R or ZR)[?mtu=auto|words]";
+
+/// Maximum number of words in a single batch read or write, unless lowered
+/// by the `mtu` option.
+const MAX_WORDS: usize = 960;
+
+const CMD_READ: u16 = 0x0401;
+const CMD_WRITE: u16 = 0x1401;
+
+pub struct SlmpProto {
+    host: String,
+    port: u16,
+    device_code: u8,
+    base: u32,
+    stream: Option<TcpStream>,
+    timeouts: Timeouts,
+    cancel: Cancel,
+    errors: ErrorLog,
+    offset: usize,
+    max_words: usize,
+    tune: bool,
+}
+
+impl SlmpProto {
+    pub fn new(addr: &str) -> Result<Self> {
+        let err0 = || Error::InvalidAddress(SLMP_ADDR_FMT);
+        let err1 = |_| Error::InvalidAddress(SLMP_ADDR_FMT);
+        let caps = SLMP_ADDR_RE.captures(addr).ok_or_else(err0)?;
+        let host = caps[1].into();
+        let port = caps[2].parse().map_err(err1)?;
+        // W devices are numbered in hex, the others in decimal
+        let (device_code, radix) = match &caps[3] {
+            "D" => (0xA8, 10),
+            "W" => (0xB4, 16),
+            "R" => (0xAF, 10),
+            _ => (0xB0, 10),
+        };
+        let base = u32::from_str_radix(&caps[4], radix).map_err(err1)?;
+        if base > 0xFF_FFFF {
+            return Err(err0());
+        }
+        let mut max_words = MAX_WORDS;
+        let mut tune = false;
+        for (key, value) in parse_query(caps.get(5).map(|m| m.as_str())) {
+            match key {
+                "mtu" if value == "auto" => tune = true,
+                "mtu" => max_words = value.parse::<usize>().map_err(err1)?.min(MAX_WORDS).max(1),
+                _ => return Err(err0()),
+            }
+        }
+
+        Ok(Self { host, port, device_code, base, stream: None, offset: 0, max_words, tune,
+                  timeouts: Timeouts::default(), cancel: Cancel::default(),
+                  errors: ErrorLog::default() })
+    }
+
+    /// Use the given timeouts instead of the default ones.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Abort pending requests when the given token is cancelled.
+    pub fn with_cancel(mut self, token: CancelToken) -> Self {
+        self.cancel = Cancel::new(token);
+        self
+    }
+
+    fn transact(&mut self, command: u16, word: u32, count: usize,
+                payload: &[u8]) -> Result<Vec<u8>> {
+        self.cancel.check()?;
+        if self.stream.is_none() || self.cancel.is_shut_down() {
+            self.reconnect()?;
+        }
+        let stream = self.stream.as_mut().unwrap();
+        // monitoring timer is in units of 250 ms
+        let timer = (self.timeouts.read.as_millis() / 250).max(1) as u16;
+        let dev = self.base + word;
+
+        let mut frame = vec![0x50, 0x00, 0x00, 0xFF, 0xFF, 0x03, 0x00];
+        frame.extend_from_slice(&(12 + payload.len() as u16).to_le_bytes());
+        frame.extend_from_slice(&timer.to_le_bytes());
+        frame.extend_from_slice(&command.to_le_bytes());
+        frame.extend_from_slice(&[0x00, 0x00]);
+        frame.extend_from_slice(&dev.to_le_bytes()[..3]);
+        frame.push(self.device_code);
+        frame.extend_from_slice(&(count as u16).to_le_bytes());
+        frame.extend_from_slice(payload);
+
+        let result = stream.write_all(&frame).and_then(|_| {
+            let mut header = [0; 11];
+            stream.read_exact(&mut header)?;
+            let length = u16::from_le_bytes([header[7], header[8]]) as usize;
+            let mut reply = vec![0; length.saturating_sub(2)];
+            stream.read_exact(&mut reply)?;
+            Ok((header, reply))
+        });
+        match result {
+            Ok((header, reply)) => {
+                if header[0] != 0xD0 {
+                    self.disconnect();
+                    return Err(Error::SLMP("invalid response frame".into()));
+                }
+                let end_code = u16::from_le_bytes([header[9], header[10]]);
+                if end_code != 0 {
+                    return Err(Error::SLMP(format!("end code {:#06x}", end_code)));
+                }
+                Ok(reply)
+            }
+            Err(ioe) => {
+                self.disconnect();
+                self.errors.report(format!("during SLMP request: {}", ioe));
+                Err(Error::Wrapped(Box::new(ioe.into()),
+                                   if command == CMD_READ { "read" } else { "write" }))
+            }
+        }
+    }
+
+    /// Size of the image in bytes, device numbers have 24 bits.
+    fn image_size(&self) -> usize {
+        (2 * (0x100_0000 - self.base as usize)).saturating_sub(self.offset)
+    }
+
+    fn read_words(&mut self, word: u32, data: &mut [u8]) -> Result<()> {
+        for (i, chunk) in data.chunks_mut(2 * self.max_words).enumerate() {
+            let start = word + (i * self.max_words) as u32;
+            let reply = self.transact(CMD_READ, start, chunk.len() / 2, &[])?;
+            if reply.len() != chunk.len() {
+                return Err(Error::SLMP("unexpected reply size".into()));
+            }
+            chunk.copy_from_slice(&reply);
+        }
+        Ok(())
+    }
+}
+
+impl Protocol for SlmpProto {
+    fn get_offsets(&self) -> &'static [usize] {
+        &[0]
+    }
+
+    fn set_offset(&mut self, offset: usize) {
+        self.offset = offset;
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        self.cancel.check()?;
+        let addr = (self.host.as_str(), self.port).to_socket_addrs()?.next()
+            .ok_or_else(|| Error::InvalidAddress(SLMP_ADDR_FMT))?;
+        let stream = TcpStream::connect_timeout(&addr, self.timeouts.connect)?;
+        stream.set_read_timeout(Some(self.timeouts.read))?;
+        stream.set_write_timeout(Some(self.timeouts.write))?;
+        stream.set_nodelay(true)?;
+        self.cancel.watch(&stream)?;
+        self.stream = Some(stream);
+
+        self.errors.flush();
+        log::info!("connected to {}:{}", self.host, self.port);
+        if self.tune {
+            // failed probes can reconnect, which must not probe again
+            self.tune = false;
+            self.max_words = MAX_WORDS;
+            let mut buf = vec![0; 2 * MAX_WORDS];
+            self.max_words = probe_request_size(1, MAX_WORDS, 1, |n| {
+                self.read_words(0, &mut buf[..2*n]).is_ok()
+            });
+            self.tune = true;
+        }
+        Ok(())
+    }
+
+    fn preflight(&mut self) -> Preflight {
+        let mut report = Preflight::default();
+        if report.tcp(&self.host, self.port, self.timeouts.connect) {
+            report.connect_and_read(self);
+        }
+        report
+    }
+
+    fn disconnect(&mut self) {
+        self.stream = None;
+        self.cancel.release();
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        check_length(addr, data.len(), self.image_size())?;
+        // word devices hold the image bytes in little-endian order, so we can
+        // copy them directly, but need whole words
+        let addr = self.offset + addr;
+        let first = addr / 2;
+        let last = (addr + data.len() + 1) / 2;
+        let mut buf = vec![0; 2 * (last - first)];
+        self.read_words(first as u32, &mut buf)?;
+        let skip = addr % 2;
+        data.copy_from_slice(&buf[skip..skip + data.len()]);
+        Ok(())
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        check_length(addr, data.len(), self.image_size())?;
+        let addr = self.offset + addr;
+        let first = addr / 2;
+        let last = (addr + data.len() + 1) / 2;
+        let mut buf = vec![0; 2 * (last - first)];
+        let skip = addr % 2;
+        // complete partially written words with the current contents
+        if skip != 0 {
+            self.read_words(first as u32, &mut buf[..2])?;
+        }
+        if (addr + data.len()) % 2 != 0 {
+            let n = buf.len();
+            self.read_words((last - 1) as u32, &mut buf[n-2..])?;
+        }
+        buf[skip..skip + data.len()].copy_from_slice(data);
+        for (i, chunk) in buf.chunks(2 * self.max_words).enumerate() {
+            let start = (first + i * self.max_words) as u32;
+            self.transact(CMD_WRITE, start, chunk.len() / 2, chunk)?;
+        }
+        Ok(())
+    }
+}
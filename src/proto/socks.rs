@@ -0,0 +1,179 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! SOCKS5 proxy support for TCP based protocols.
+//!
+//! Since the `modbus` and `ads` crates make their own connections, the proxy
+//! is used via a local relay: the client connects to a port on localhost and
+//! each connection is forwarded through the proxy to the real target.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use itertools::Itertools;
+
+use crate::{Error, Result};
+use crate::proto::Timeouts;
+
+/// A SOCKS5 proxy specification, parsed from `[user:password@]host:port`.
+#[derive(Debug, Clone)]
+pub struct Socks5Proxy {
+    host: String,
+    port: u16,
+    auth: Option<(String, String)>,
+}
+
+const SOCKS_FMT: &str = "socks5=[user:password@]host:port";
+
+impl Socks5Proxy {
+    pub fn new(spec: &str) -> Result<Self> {
+        let err = || Error::InvalidAddress(SOCKS_FMT);
+        let (auth, hostport) = match spec.rfind('@') {
+            Some(i) => {
+                let (user, pass) = spec[..i].splitn(2, ':').collect_tuple().ok_or_else(err)?;
+                (Some((user.into(), pass.into())), &spec[i+1..])
+            }
+            None => (None, spec),
+        };
+        let (port, host) = hostport.rsplitn(2, ':').collect_tuple().ok_or_else(err)?;
+        let port = port.parse().map_err(|_| err())?;
+        Ok(Self { host: host.into(), port, auth })
+    }
+
+    /// Connect to the target through the proxy, using the connect timeout
+    /// for the proxy and the read/write timeouts for the handshake.
+    pub fn connect(&self, host: &str, port: u16, timeouts: Timeouts) -> io::Result<TcpStream> {
+        let proxy = (self.host.as_str(), self.port).to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "proxy host not found"))?;
+        let mut stream = TcpStream::connect_timeout(&proxy, timeouts.connect)?;
+        stream.set_read_timeout(Some(timeouts.read))?;
+        stream.set_write_timeout(Some(timeouts.write))?;
+        let fail = |msg: &str| io::Error::new(ErrorKind::ConnectionRefused,
+                                              format!("SOCKS5 proxy: {}", msg));
+
+        let method = if self.auth.is_some() { 2 } else { 0 };
+        stream.write_all(&[5, 1, method])?;
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply)?;
+        if reply != [5, method] {
+            return Err(fail("no acceptable authentication method"));
+        }
+        if let Some((user, pass)) = &self.auth {
+            let mut req = vec![1, user.len() as u8];
+            req.extend_from_slice(user.as_bytes());
+            req.push(pass.len() as u8);
+            req.extend_from_slice(pass.as_bytes());
+            stream.write_all(&req)?;
+            stream.read_exact(&mut reply)?;
+            if reply[1] != 0 {
+                return Err(fail("authentication failed"));
+            }
+        }
+
+        let mut req = vec![5, 1, 0, 3, host.len() as u8];
+        req.extend_from_slice(host.as_bytes());
+        req.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&req)?;
+        let mut reply = [0; 5];
+        stream.read_exact(&mut reply)?;
+        if reply[1] != 0 {
+            return Err(fail(&format!("connect failed with code {}", reply[1])));
+        }
+        // skip the bound address, whose length depends on its type
+        let rest = match reply[3] {
+            1 => 4 + 2 - 1,
+            4 => 16 + 2 - 1,
+            _ => reply[4] as usize + 2,
+        };
+        stream.read_exact(&mut vec![0; rest])?;
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+        Ok(stream)
+    }
+}
+
+/// A local relay forwarding all connections to a target via a proxy.
+pub struct Socks5Relay {
+    port: u16,
+    quit: Arc<AtomicBool>,
+}
+
+impl Socks5Relay {
+    pub fn start(proxy: Socks5Proxy, host: &str, port: u16,
+                 timeouts: Timeouts) -> Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", 0))?;
+        let local_port = listener.local_addr()?.port();
+        let quit = Arc::new(AtomicBool::new(false));
+        let quit2 = quit.clone();
+        let host = host.to_string();
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                if quit2.load(Ordering::Relaxed) {
+                    break;
+                }
+                let client = match client {
+                    Ok(client) => client,
+                    Err(_) => continue,
+                };
+                match proxy.connect(&host, port, timeouts) {
+                    Ok(remote) => if let Err(e) = pump(client, remote) {
+                        log::error!("SOCKS5 relay: {}", e);
+                    },
+                    Err(e) => log::error!("connecting to {}:{} via proxy: {}", host, port, e),
+                }
+            }
+        });
+        Ok(Self { port: local_port, quit })
+    }
+
+    /// The local port to connect to.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl Drop for Socks5Relay {
+    fn drop(&mut self) {
+        self.quit.store(true, Ordering::Relaxed);
+        // wake up the listener thread
+        let _ = TcpStream::connect(("127.0.0.1", self.port));
+    }
+}
+
+/// Copy data in both directions until one side closes.
+fn pump(client: TcpStream, remote: TcpStream) -> io::Result<()> {
+    let (mut client_rd, mut remote_wr) = (client.try_clone()?, remote.try_clone()?);
+    let (mut remote_rd, mut client_wr) = (remote, client);
+    thread::spawn(move || {
+        let _ = io::copy(&mut client_rd, &mut remote_wr);
+        let _ = remote_wr.shutdown(Shutdown::Both);
+    });
+    thread::spawn(move || {
+        let _ = io::copy(&mut remote_rd, &mut client_wr);
+        let _ = client_wr.shutdown(Shutdown::Both);
+    });
+    Ok(())
+}
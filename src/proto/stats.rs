@@ -0,0 +1,222 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! Request statistics, for choosing poll intervals and diagnosing slow links.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::Result;
+use crate::proto::{Preflight, Protocol};
+
+/// Number of recent requests the percentile is computed from.
+const SAMPLES: usize = 1000;
+
+/// Request timing, min/avg/max since the last reset, p95 of recent requests.
+#[derive(Debug, Clone, Copy)]
+pub struct Latency {
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+    pub p95: Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    pub reads: u64,
+    pub writes: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub errors: u64,
+    /// Connects requested through the wrapper.  Reconnects that the backend
+    /// does on its own are not counted.
+    pub connects: u64,
+    /// None if no request has been made yet.
+    pub latency: Option<Latency>,
+}
+
+pub struct Measured<P> {
+    inner: P,
+    stats: Stats,
+    total: Duration,
+    min: Duration,
+    max: Duration,
+    recent: VecDeque<Duration>,
+}
+
+impl<P: Protocol> Measured<P> {
+    pub fn new(inner: P) -> Self {
+        Self { inner, stats: Stats::default(), total: Duration::default(),
+               min: Duration::default(), max: Duration::default(), recent: VecDeque::new() }
+    }
+
+    /// Return the statistics since creation or the last reset.
+    pub fn stats(&self) -> Stats {
+        let count = self.recent.len();
+        let latency = if count == 0 { None } else {
+            let requests = self.stats.reads + self.stats.writes;
+            let mut recent = self.recent.iter().copied().collect::<Vec<_>>();
+            recent.sort();
+            Some(Latency {
+                min: self.min,
+                avg: self.total / requests as u32,
+                max: self.max,
+                p95: recent[(count * 95 / 100).min(count - 1)],
+            })
+        };
+        Stats { latency, ..self.stats.clone() }
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = Stats::default();
+        self.total = Duration::default();
+        self.recent.clear();
+    }
+
+    /// Run an operation as one request, counting it as a read or write.
+    fn run<T>(&mut self, write: bool, bytes: usize,
+              op: impl FnOnce(&mut P) -> Result<T>) -> Result<T> {
+        let start = Instant::now();
+        let result = op(&mut self.inner);
+        let elapsed = start.elapsed();
+        if self.recent.is_empty() {
+            self.min = elapsed;
+            self.max = elapsed;
+        } else {
+            self.min = self.min.min(elapsed);
+            self.max = self.max.max(elapsed);
+        }
+        if self.recent.len() == SAMPLES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(elapsed);
+        self.total += elapsed;
+        if write {
+            self.stats.writes += 1;
+        } else {
+            self.stats.reads += 1;
+        }
+        match &result {
+            Ok(_) if write => self.stats.bytes_written += bytes as u64,
+            Ok(_) => self.stats.bytes_read += bytes as u64,
+            Err(_) => self.stats.errors += 1,
+        }
+        result
+    }
+}
+
+impl<P> Measured<P> {
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+}
+
+impl<P: Protocol> Protocol for Measured<P> {
+    fn get_offsets(&self) -> &'static [usize] {
+        self.inner.get_offsets()
+    }
+
+    fn set_offset(&mut self, offset: usize) {
+        self.inner.set_offset(offset);
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        self.stats.connects += 1;
+        self.inner.connect()
+    }
+
+    fn disconnect(&mut self) {
+        self.inner.disconnect();
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.stats.connects += 1;
+        self.inner.reconnect()
+    }
+
+    fn preflight(&mut self) -> Preflight {
+        self.inner.preflight()
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        let len = data.len();
+        self.run(false, len, |p| p.read_into(addr, data))
+    }
+
+    fn read_ranges(&mut self, ranges: &[(usize, usize)]) -> Result<Vec<Vec<u8>>> {
+        let len = ranges.iter().map(|&(_, length)| length).sum();
+        self.run(false, len, |p| p.read_ranges(ranges))
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        self.run(true, data.len(), |p| p.write(addr, data))
+    }
+
+    fn write_masked(&mut self, addr: usize, data: &[u8], mask: &[u8]) -> Result<()> {
+        self.run(true, data.len(), |p| p.write_masked(addr, data, mask))
+    }
+
+    fn read_bit(&mut self, addr: usize, bit: u8) -> Result<bool> {
+        self.run(false, 1, |p| p.read_bit(addr, bit))
+    }
+
+    fn write_bit(&mut self, addr: usize, bit: u8, value: bool) -> Result<()> {
+        self.run(true, 1, |p| p.write_bit(addr, bit, value))
+    }
+
+    fn write_ranges(&mut self, writes: &[(usize, &[u8])]) -> Result<()> {
+        let len = writes.iter().map(|(_, data)| data.len()).sum();
+        self.run(true, len, |p| p.write_ranges(writes))
+    }
+
+    fn write_read(&mut self, addr: usize, data: &[u8], read_addr: usize,
+                  result: &mut [u8]) -> Result<()> {
+        let len = result.len();
+        let res = self.run(true, data.len(), |p| p.write_read(addr, data, read_addr, result));
+        if res.is_ok() {
+            self.stats.bytes_read += len as u64;
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakePlc, OVERRIDES, call_overrides};
+
+    #[test]
+    fn forwards_overrides() {
+        let mut proto = Measured::new(FakePlc::new(4));
+        call_overrides(&mut proto);
+        assert_eq!(proto.inner().calls(), OVERRIDES);
+    }
+}
@@ -22,7 +22,7 @@
 // *****************************************************************************
 
 use crate::{Error, Result};
-use crate::proto::Protocol;
+use crate::proto::{Protocol, check_length};
 
 use tango_client::{CommandData, DeviceProxy};
 use regex::Regex;
@@ -50,7 +50,7 @@ impl TangoProto {
 }
 
 impl Protocol for TangoProto {
-    fn get_offsets() -> &'static [usize] {
+    fn get_offsets(&self) -> &'static [usize] {
         &[0]
     }
 
@@ -81,6 +81,7 @@ impl Protocol for TangoProto {
     }
 
     fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        check_length(addr, data.len(), u32::MAX as usize)?;
         if self.device.is_none() {
             self.reconnect()?;
         }
@@ -89,16 +90,18 @@ impl Protocol for TangoProto {
         // TODO: log + wrap errors
         let result = device.command_inout("ReadInputBytes",
                                           CommandData::ULongArray(arg))?;
-        if let CommandData::CharArray(res) = result {
-            if res.len() == data.len() {
+        match result {
+            CommandData::CharArray(res) if res.len() == data.len() => {
                 data.copy_from_slice(&res);
+                Ok(())
             }
-            return Ok(());
+            CommandData::CharArray(_) => Err(Error::TangoProto("Invalid length returned")),
+            _ => Err(Error::TangoProto("Invalid data type returned")),
         }
-        return Err(Error::TangoProto("Invalid data type or length returned"));
     }
 
     fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        check_length(addr, data.len(), u32::MAX as usize)?;
         if self.device.is_none() {
             self.reconnect()?;
         }
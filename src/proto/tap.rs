@@ -0,0 +1,149 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! Mirroring of all read data to a secondary sink, for capturing the image
+//! in the background.
+
+use std::io::Write;
+use std::time::SystemTime;
+
+use crate::Result;
+use crate::proto::{Preflight, Protocol};
+use crate::proto::replay::{Kind, LOG_MAGIC, Record};
+
+pub struct Tap<P> {
+    inner: P,
+    sink: Box<dyn FnMut(usize, &[u8]) + Send>,
+}
+
+impl<P: Protocol> Tap<P> {
+    /// Call `sink` with the address and data of every successful read.
+    pub fn new(inner: P, sink: impl FnMut(usize, &[u8]) + Send + 'static) -> Self {
+        Self { inner, sink: Box::new(sink) }
+    }
+
+    /// Write every successful read to `writer` (e.g. a file or socket) in the
+    /// transaction log format, which can be played back with `replay://`.
+    pub fn to_writer(inner: P, mut writer: impl Write + Send + 'static) -> Result<Self> {
+        writer.write_all(LOG_MAGIC)?;
+        Ok(Self::new(inner, move |addr, data| {
+            let record = Record { time: SystemTime::now(), kind: Kind::Read, ok: true,
+                                  addr, data: data.to_vec() };
+            if let Err(e) = record.write_to(&mut writer) {
+                log::warn!("could not mirror read data: {}", e);
+            }
+        }))
+    }
+}
+
+impl<P> Tap<P> {
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+}
+
+impl<P: Protocol> Protocol for Tap<P> {
+    fn get_offsets(&self) -> &'static [usize] {
+        self.inner.get_offsets()
+    }
+
+    fn set_offset(&mut self, offset: usize) {
+        self.inner.set_offset(offset);
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        self.inner.connect()
+    }
+
+    fn disconnect(&mut self) {
+        self.inner.disconnect();
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.inner.reconnect()
+    }
+
+    fn preflight(&mut self) -> Preflight {
+        self.inner.preflight()
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        self.inner.read_into(addr, data)?;
+        (self.sink)(addr, data);
+        Ok(())
+    }
+
+    fn read_ranges(&mut self, ranges: &[(usize, usize)]) -> Result<Vec<Vec<u8>>> {
+        let result = self.inner.read_ranges(ranges)?;
+        for (&(addr, _), data) in ranges.iter().zip(&result) {
+            (self.sink)(addr, data);
+        }
+        Ok(result)
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        self.inner.write(addr, data)
+    }
+
+    fn write_masked(&mut self, addr: usize, data: &[u8], mask: &[u8]) -> Result<()> {
+        self.inner.write_masked(addr, data, mask)
+    }
+
+    fn write_bit(&mut self, addr: usize, bit: u8, value: bool) -> Result<()> {
+        self.inner.write_bit(addr, bit, value)
+    }
+
+    fn write_ranges(&mut self, writes: &[(usize, &[u8])]) -> Result<()> {
+        self.inner.write_ranges(writes)
+    }
+
+    fn write_read(&mut self, addr: usize, data: &[u8], read_addr: usize,
+                  result: &mut [u8]) -> Result<()> {
+        self.inner.write_read(addr, data, read_addr, result)?;
+        (self.sink)(read_addr, result);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakePlc, call_overrides};
+
+    #[test]
+    fn forwards_overrides() {
+        let mut proto = Tap::new(FakePlc::new(4), |_, _| ());
+        call_overrides(&mut proto);
+        // read_bit is left to the default, so that the byte is tapped
+        assert_eq!(proto.inner().calls(), ["read_into", "write_bit", "write_masked",
+                                           "read_ranges", "write_ranges", "write_read"]);
+    }
+}
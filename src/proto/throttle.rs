@@ -0,0 +1,179 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! Limiting the request rate, for PLCs and couplers that can't keep up.
+//!
+//! Each call to the wrapped protocol counts as one request, so batched
+//! requests are limited as a whole, even if the backend needs more than one
+//! round-trip for them.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::Result;
+use crate::proto::{Preflight, Protocol};
+
+/// How many requests are allowed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimit {
+    /// Maximum average number of requests per second, with up to this many
+    /// requests in a burst.
+    pub max_rate: Option<f64>,
+    /// Minimum time between the start of two requests.
+    pub min_gap: Duration,
+}
+
+pub struct Throttled<P> {
+    inner: P,
+    limit: RateLimit,
+    last: Option<Instant>,
+    // token bucket for the rate limit
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl<P: Protocol> Throttled<P> {
+    pub fn new(inner: P, limit: RateLimit) -> Self {
+        let tokens = limit.max_rate.map_or(0., |rate| rate.max(1.));
+        Self { inner, limit, last: None, tokens, refilled: Instant::now() }
+    }
+
+    /// Wait until the next request is allowed.
+    fn throttle(&mut self) {
+        let now = Instant::now();
+        let mut delay = match self.last {
+            Some(last) => self.limit.min_gap.checked_sub(now - last).unwrap_or_default(),
+            None => Duration::default(),
+        };
+        if let Some(rate) = self.limit.max_rate.filter(|&rate| rate > 0.) {
+            let elapsed = (now - self.refilled).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate).min(rate.max(1.));
+            self.refilled = now;
+            if self.tokens < 1. {
+                delay = delay.max(Duration::from_secs_f64((1. - self.tokens) / rate));
+            }
+            // the time waited is added back on the next refill
+            self.tokens -= 1.;
+        }
+        if delay > Duration::default() {
+            log::debug!("throttling request by {:?}", delay);
+            thread::sleep(delay);
+        }
+        self.last = Some(Instant::now());
+    }
+}
+
+impl<P> Throttled<P> {
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+}
+
+impl<P: Protocol> Protocol for Throttled<P> {
+    fn get_offsets(&self) -> &'static [usize] {
+        self.inner.get_offsets()
+    }
+
+    fn set_offset(&mut self, offset: usize) {
+        self.inner.set_offset(offset);
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        self.inner.connect()
+    }
+
+    fn disconnect(&mut self) {
+        self.inner.disconnect();
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.inner.reconnect()
+    }
+
+    fn preflight(&mut self) -> Preflight {
+        self.inner.preflight()
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        self.throttle();
+        self.inner.read_into(addr, data)
+    }
+
+    fn read_ranges(&mut self, ranges: &[(usize, usize)]) -> Result<Vec<Vec<u8>>> {
+        self.throttle();
+        self.inner.read_ranges(ranges)
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        self.throttle();
+        self.inner.write(addr, data)
+    }
+
+    fn write_masked(&mut self, addr: usize, data: &[u8], mask: &[u8]) -> Result<()> {
+        self.throttle();
+        self.inner.write_masked(addr, data, mask)
+    }
+
+    fn write_ranges(&mut self, writes: &[(usize, &[u8])]) -> Result<()> {
+        self.throttle();
+        self.inner.write_ranges(writes)
+    }
+
+    fn write_read(&mut self, addr: usize, data: &[u8], read_addr: usize,
+                  result: &mut [u8]) -> Result<()> {
+        self.throttle();
+        self.inner.write_read(addr, data, read_addr, result)
+    }
+
+    fn read_bit(&mut self, addr: usize, bit: u8) -> Result<bool> {
+        self.throttle();
+        self.inner.read_bit(addr, bit)
+    }
+
+    fn write_bit(&mut self, addr: usize, bit: u8, value: bool) -> Result<()> {
+        self.throttle();
+        self.inner.write_bit(addr, bit, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakePlc, OVERRIDES, call_overrides};
+
+    #[test]
+    fn forwards_overrides() {
+        let limit = RateLimit { max_rate: None, min_gap: Duration::default() };
+        let mut proto = Throttled::new(FakePlc::new(4), limit);
+        call_overrides(&mut proto);
+        assert_eq!(proto.inner().calls(), OVERRIDES);
+    }
+}
@@ -0,0 +1,138 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! Instrumentation with `tracing` spans, enabled by the "tracing" feature.
+//!
+//! Every request runs in a span carrying the PLC address, the operation,
+//! the image address and length, and whether it succeeded.  Protocols
+//! created with `zapf::connect` are instrumented automatically.
+
+use tracing::field;
+
+use crate::Result;
+use crate::proto::{Preflight, Protocol};
+
+pub struct Traced<P> {
+    inner: P,
+    plc: String,
+}
+
+impl<P: Protocol> Traced<P> {
+    /// Wrap a protocol, with `plc` (normally the address) recorded in
+    /// each span to tell connections apart.
+    pub fn new(inner: P, plc: impl Into<String>) -> Self {
+        Self { inner, plc: plc.into() }
+    }
+
+    fn run<T>(&mut self, op: &'static str, addr: usize, len: usize,
+              f: impl FnOnce(&mut P) -> Result<T>) -> Result<T> {
+        let span = tracing::debug_span!("plc_request", plc = %self.plc, op, addr, len,
+                                        ok = field::Empty);
+        let _enter = span.enter();
+        let result = f(&mut self.inner);
+        span.record("ok", &result.is_ok());
+        if let Err(e) = &result {
+            tracing::warn!(error = %e, "{} failed", op);
+        }
+        result
+    }
+}
+
+impl<P> Traced<P> {
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+}
+
+impl<P: Protocol> Protocol for Traced<P> {
+    fn get_offsets(&self) -> &'static [usize] {
+        self.inner.get_offsets()
+    }
+
+    fn set_offset(&mut self, offset: usize) {
+        self.inner.set_offset(offset);
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        self.run("connect", 0, 0, |p| p.connect())
+    }
+
+    fn disconnect(&mut self) {
+        tracing::debug!(plc = %self.plc, "disconnect");
+        self.inner.disconnect();
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.run("reconnect", 0, 0, |p| p.reconnect())
+    }
+
+    fn preflight(&mut self) -> Preflight {
+        self.inner.preflight()
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        let len = data.len();
+        self.run("read", addr, len, |p| p.read_into(addr, data))
+    }
+
+    fn read_ranges(&mut self, ranges: &[(usize, usize)]) -> Result<Vec<Vec<u8>>> {
+        let start = ranges.first().map_or(0, |&(addr, _)| addr);
+        let len = ranges.iter().map(|&(_, length)| length).sum();
+        self.run("read_ranges", start, len, |p| p.read_ranges(ranges))
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        self.run("write", addr, data.len(), |p| p.write(addr, data))
+    }
+
+    fn write_masked(&mut self, addr: usize, data: &[u8], mask: &[u8]) -> Result<()> {
+        self.run("write_masked", addr, data.len(), |p| p.write_masked(addr, data, mask))
+    }
+
+    fn read_bit(&mut self, addr: usize, bit: u8) -> Result<bool> {
+        self.run("read_bit", addr, 1, |p| p.read_bit(addr, bit))
+    }
+
+    fn write_bit(&mut self, addr: usize, bit: u8, value: bool) -> Result<()> {
+        self.run("write_bit", addr, 1, |p| p.write_bit(addr, bit, value))
+    }
+
+    fn write_ranges(&mut self, writes: &[(usize, &[u8])]) -> Result<()> {
+        let start = writes.first().map_or(0, |&(addr, _)| addr);
+        let len = writes.iter().map(|(_, data)| data.len()).sum();
+        self.run("write_ranges", start, len, |p| p.write_ranges(writes))
+    }
+
+    fn write_read(&mut self, addr: usize, data: &[u8], read_addr: usize,
+                  result: &mut [u8]) -> Result<()> {
+        self.run("write_read", addr, data.len(), |p| p.write_read(addr, data, read_addr, result))
+    }
+}
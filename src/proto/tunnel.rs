@@ -0,0 +1,232 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! Connecting via an SSH port forward through a jump host.
+//!
+//! The tunnel is established by running the system's `ssh` client, so that
+//! the usual configuration (keys, agent, `~/.ssh/config`) applies.  It must
+//! be able to authenticate non-interactively.
+
+use std::io::{Error as IoError, ErrorKind};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{Error, Result};
+use crate::proto::{Preflight, Protocol, Timeouts};
+
+use regex::Regex;
+use once_cell::sync::Lazy;
+
+static SSH_ADDR_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^(\w+)\+ssh://([^!]+?)(?::(\d+))?!([^/:!]+):(\d+)(.*)$")
+        .expect("invalid regex")
+});
+const SSH_ADDR_FMT: &str = "scheme+ssh://[user@]jumphost[:sshport]!host:port[/rest]";
+
+/// Time to wait for the SSH client to establish the forward.
+const TUNNEL_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct SshTunnel<P> {
+    jumphost: String,
+    ssh_port: Option<u16>,
+    target: String,
+    local_port: u16,
+    child: Option<Child>,
+    timeouts: Timeouts,
+    inner: P,
+}
+
+impl<P: Protocol> SshTunnel<P> {
+    /// Create a tunnel from an address like `modbus+ssh://jumphost!plc:502/0`.
+    ///
+    /// The inner protocol is created by `make` from the address rewritten to
+    /// point to the local end of the tunnel, e.g. `modbus://127.0.0.1:N/0`.
+    pub fn new(addr: &str, make: impl FnOnce(&str) -> Result<P>) -> Result<Self> {
+        let err0 = || Error::InvalidAddress(SSH_ADDR_FMT);
+        let err1 = |_| Error::InvalidAddress(SSH_ADDR_FMT);
+        let caps = SSH_ADDR_RE.captures(addr).ok_or_else(err0)?;
+        let ssh_port = caps.get(3).map(|p| p.as_str().parse()).transpose().map_err(err1)?;
+        let target_port: u16 = caps[5].parse().map_err(err1)?;
+
+        // find a free local port to forward from
+        let local_port = TcpListener::bind(("127.0.0.1", 0))?.local_addr()?.port();
+        let inner = make(&format!("{}://127.0.0.1:{}{}", &caps[1], local_port, &caps[6]))?;
+
+        Ok(Self {
+            jumphost: caps[2].into(),
+            ssh_port,
+            target: format!("{}:{}", &caps[4], target_port),
+            local_port,
+            child: None,
+            timeouts: Timeouts::default(),
+            inner,
+        })
+    }
+
+    /// Use the given timeouts for checking the local end of the tunnel.  The
+    /// inner protocol's timeouts are set by `make` as usual.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Make sure the SSH client is running, (re)starting it if necessary.
+    fn ensure_tunnel(&mut self) -> Result<()> {
+        if let Some(child) = &mut self.child {
+            match child.try_wait()? {
+                None => return Ok(()),
+                Some(status) => log::warn!("SSH tunnel via {} exited ({})", self.jumphost, status),
+            }
+        }
+        self.child = None;
+
+        let mut cmd = Command::new("ssh");
+        cmd.arg("-N")
+           .args(&["-o", "BatchMode=yes", "-o", "ExitOnForwardFailure=yes"])
+           .arg("-L").arg(format!("127.0.0.1:{}:{}", self.local_port, self.target));
+        if let Some(port) = self.ssh_port {
+            cmd.arg("-p").arg(port.to_string());
+        }
+        let mut child = cmd.arg(&self.jumphost)
+                           .stdin(Stdio::null())
+                           .stdout(Stdio::null())
+                           .spawn()?;
+
+        // wait until the forward accepts connections
+        let start = Instant::now();
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Err(IoError::new(ErrorKind::ConnectionRefused,
+                                        format!("SSH tunnel failed ({})", status)).into());
+            }
+            let local = ([127, 0, 0, 1], self.local_port).into();
+            if TcpStream::connect_timeout(&local, self.timeouts.connect).is_ok() {
+                break;
+            }
+            if start.elapsed() > TUNNEL_TIMEOUT {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(IoError::new(ErrorKind::TimedOut,
+                                        "SSH tunnel was not established in time").into());
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        log::info!("SSH tunnel to {} via {} established", self.target, self.jumphost);
+        self.child = Some(child);
+        Ok(())
+    }
+}
+
+impl<P> SshTunnel<P> {
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    fn stop_tunnel(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+impl<P> Drop for SshTunnel<P> {
+    fn drop(&mut self) {
+        self.stop_tunnel();
+    }
+}
+
+impl<P: Protocol> Protocol for SshTunnel<P> {
+    fn get_offsets(&self) -> &'static [usize] {
+        self.inner.get_offsets()
+    }
+
+    fn set_offset(&mut self, offset: usize) {
+        self.inner.set_offset(offset);
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        self.ensure_tunnel()?;
+        self.inner.connect()
+    }
+
+    fn disconnect(&mut self) {
+        self.inner.disconnect();
+        self.stop_tunnel();
+    }
+
+    fn preflight(&mut self) -> Preflight {
+        let mut report = Preflight::default();
+        if report.check(format!("SSH tunnel via {}", self.jumphost), self.ensure_tunnel()) {
+            report.checks.extend(self.inner.preflight().checks);
+        }
+        report
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        self.ensure_tunnel()?;
+        self.inner.read_into(addr, data)
+    }
+
+    fn read_ranges(&mut self, ranges: &[(usize, usize)]) -> Result<Vec<Vec<u8>>> {
+        self.ensure_tunnel()?;
+        self.inner.read_ranges(ranges)
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        self.ensure_tunnel()?;
+        self.inner.write(addr, data)
+    }
+
+    fn write_ranges(&mut self, writes: &[(usize, &[u8])]) -> Result<()> {
+        self.ensure_tunnel()?;
+        self.inner.write_ranges(writes)
+    }
+
+    fn write_read(&mut self, addr: usize, data: &[u8], read_addr: usize,
+                  result: &mut [u8]) -> Result<()> {
+        self.ensure_tunnel()?;
+        self.inner.write_read(addr, data, read_addr, result)
+    }
+
+    fn write_masked(&mut self, addr: usize, data: &[u8], mask: &[u8]) -> Result<()> {
+        self.ensure_tunnel()?;
+        self.inner.write_masked(addr, data, mask)
+    }
+
+    fn read_bit(&mut self, addr: usize, bit: u8) -> Result<bool> {
+        self.ensure_tunnel()?;
+        self.inner.read_bit(addr, bit)
+    }
+
+    fn write_bit(&mut self, addr: usize, bit: u8, value: bool) -> Result<()> {
+        self.ensure_tunnel()?;
+        self.inner.write_bit(addr, bit, value)
+    }
+}
@@ -0,0 +1,168 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! Verification of reads by reading twice, for diagnosing gateways that
+//! corrupt data.
+//!
+//! Note that values the PLC changes between the two reads are reported as
+//! mismatches too, so this is most useful on data that doesn't change, or
+//! with a callback that can tell the difference.
+
+use crate::{Error, Result};
+use crate::proto::{Preflight, Protocol};
+
+pub struct Verified<P> {
+    inner: P,
+    on_mismatch: Option<Box<dyn FnMut(usize, &[u8], &[u8]) + Send>>,
+    mismatches: u64,
+}
+
+impl<P: Protocol> Verified<P> {
+    pub fn new(inner: P) -> Self {
+        Self { inner, on_mismatch: None, mismatches: 0 }
+    }
+
+    /// Report mismatches to the callback, with the address and the data of
+    /// both reads, instead of failing the read.  The first read's data is
+    /// returned in this case.
+    pub fn on_mismatch(mut self, callback: impl FnMut(usize, &[u8], &[u8]) + Send + 'static)
+                       -> Self {
+        self.on_mismatch = Some(Box::new(callback));
+        self
+    }
+
+    /// Return the number of mismatches found so far.
+    pub fn mismatches(&self) -> u64 {
+        self.mismatches
+    }
+
+    fn compare(&mut self, addr: usize, first: &[u8], second: &[u8]) -> Result<()> {
+        if first == second {
+            return Ok(());
+        }
+        self.mismatches += 1;
+        log::warn!("read of {} bytes at {} returned different data on second read",
+                   first.len(), addr);
+        match &mut self.on_mismatch {
+            Some(callback) => {
+                callback(addr, first, second);
+                Ok(())
+            }
+            None => Err(Error::ReadMismatch(addr)),
+        }
+    }
+}
+
+impl<P> Verified<P> {
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+}
+
+impl<P: Protocol> Protocol for Verified<P> {
+    fn get_offsets(&self) -> &'static [usize] {
+        self.inner.get_offsets()
+    }
+
+    fn set_offset(&mut self, offset: usize) {
+        self.inner.set_offset(offset);
+    }
+
+    fn connect(&mut self) -> Result<()> {
+        self.inner.connect()
+    }
+
+    fn disconnect(&mut self) {
+        self.inner.disconnect();
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.inner.reconnect()
+    }
+
+    fn preflight(&mut self) -> Preflight {
+        self.inner.preflight()
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        self.inner.read_into(addr, data)?;
+        let second = self.inner.read(addr, data.len())?;
+        self.compare(addr, data, &second)
+    }
+
+    fn read_ranges(&mut self, ranges: &[(usize, usize)]) -> Result<Vec<Vec<u8>>> {
+        let first = self.inner.read_ranges(ranges)?;
+        let second = self.inner.read_ranges(ranges)?;
+        for ((&(addr, _), data1), data2) in ranges.iter().zip(&first).zip(&second) {
+            self.compare(addr, data1, data2)?;
+        }
+        Ok(first)
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        self.inner.write(addr, data)
+    }
+
+    fn write_masked(&mut self, addr: usize, data: &[u8], mask: &[u8]) -> Result<()> {
+        self.inner.write_masked(addr, data, mask)
+    }
+
+    fn write_bit(&mut self, addr: usize, bit: u8, value: bool) -> Result<()> {
+        self.inner.write_bit(addr, bit, value)
+    }
+
+    fn write_ranges(&mut self, writes: &[(usize, &[u8])]) -> Result<()> {
+        self.inner.write_ranges(writes)
+    }
+
+    fn write_read(&mut self, addr: usize, data: &[u8], read_addr: usize,
+                  result: &mut [u8]) -> Result<()> {
+        self.inner.write_read(addr, data, read_addr, result)?;
+        let second = self.inner.read(read_addr, result.len())?;
+        self.compare(read_addr, result, &second)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakePlc, call_overrides};
+
+    #[test]
+    fn forwards_overrides() {
+        let mut proto = Verified::new(FakePlc::new(4));
+        call_overrides(&mut proto);
+        // read_bit is left to the default, which reads twice as well
+        assert_eq!(proto.inner().calls(), ["read_into", "read_into", "write_bit", "write_masked",
+                                           "read_ranges", "read_ranges", "write_ranges",
+                                           "write_read", "read_into"]);
+    }
+}
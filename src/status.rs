@@ -0,0 +1,207 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! Decoding of the PILS device status word.
+//!
+//! For 16-bit status words, the state is in the top four bits, followed by
+//! four reason bits and eight aux bits.  32-bit status words have the same
+//! layout in their top byte, and 24 aux bits.
+
+use std::fmt;
+
+/// The state of a device's state machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Reset,
+    Idle,
+    Disabled,
+    Warn,
+    Start,
+    Busy,
+    Stop,
+    Error,
+    DiagnosticError,
+    /// A state value not defined by the specification.
+    Unknown(u8),
+}
+
+impl State {
+    pub fn from_bits(bits: u8) -> Self {
+        match bits {
+            0 => State::Reset,
+            1 => State::Idle,
+            2 => State::Disabled,
+            3 => State::Warn,
+            5 => State::Start,
+            6 => State::Busy,
+            7 => State::Stop,
+            8 => State::Error,
+            13 => State::DiagnosticError,
+            n => State::Unknown(n),
+        }
+    }
+
+    /// Return the state's value, as in the status word.
+    pub fn bits(&self) -> u8 {
+        match *self {
+            State::Reset => 0,
+            State::Idle => 1,
+            State::Disabled => 2,
+            State::Warn => 3,
+            State::Start => 5,
+            State::Busy => 6,
+            State::Stop => 7,
+            State::Error => 8,
+            State::DiagnosticError => 13,
+            State::Unknown(n) => n,
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            State::Reset => f.write_str("RESET"),
+            State::Idle => f.write_str("IDLE"),
+            State::Disabled => f.write_str("DISABLED"),
+            State::Warn => f.write_str("WARN"),
+            State::Start => f.write_str("START"),
+            State::Busy => f.write_str("BUSY"),
+            State::Stop => f.write_str("STOP"),
+            State::Error => f.write_str("ERROR"),
+            State::DiagnosticError => f.write_str("DIAGNOSTIC_ERROR"),
+            State::Unknown(n) => write!(f, "unknown state {}", n),
+        }
+    }
+}
+
+/// Reason bits, giving more detail about the state.
+pub const REASON_INHIBIT: u8 = 0x1;
+pub const REASON_TIMEOUT: u8 = 0x2;
+pub const REASON_LOW_LIMIT: u8 = 0x4;
+pub const REASON_HIGH_LIMIT: u8 = 0x8;
+
+/// A decoded status word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    pub state: State,
+    pub reason: u8,
+    pub aux: u32,
+}
+
+impl Status {
+    pub fn from_u16(word: u16) -> Self {
+        Self {
+            state: State::from_bits((word >> 12) as u8),
+            reason: ((word >> 8) & 0xF) as u8,
+            aux: (word & 0xFF) as u32,
+        }
+    }
+
+    pub fn from_u32(word: u32) -> Self {
+        Self {
+            state: State::from_bits((word >> 28) as u8),
+            reason: ((word >> 24) & 0xF) as u8,
+            aux: word & 0xFF_FFFF,
+        }
+    }
+
+    /// Encode as a 16-bit status word, keeping only the low eight aux bits.
+    pub fn to_u16(&self) -> u16 {
+        ((self.state.bits() & 0xF) as u16) << 12 | ((self.reason & 0xF) as u16) << 8 |
+            (self.aux & 0xFF) as u16
+    }
+
+    /// Return true if the device is moving towards a new target.
+    pub fn is_busy(&self) -> bool {
+        matches!(self.state, State::Start | State::Busy | State::Stop)
+    }
+
+    pub fn has_error(&self) -> bool {
+        matches!(self.state, State::Error | State::DiagnosticError)
+    }
+
+    pub fn has_warning(&self) -> bool {
+        self.state == State::Warn
+    }
+
+    pub fn has_reason(&self, reason: u8) -> bool {
+        self.reason & reason != 0
+    }
+
+    /// Return true if the given aux bit (counted from 0) is set.
+    pub fn aux_bit(&self, bit: u8) -> bool {
+        bit < 24 && self.aux & (1 << bit) != 0
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.state)?;
+        for &(bit, name) in &[(REASON_INHIBIT, "inhibit"), (REASON_TIMEOUT, "timeout"),
+                              (REASON_LOW_LIMIT, "low limit"),
+                              (REASON_HIGH_LIMIT, "high limit")] {
+            if self.has_reason(bit) {
+                write!(f, ", {}", name)?;
+            }
+        }
+        if self.aux != 0 {
+            write!(f, ", aux {:#x}", self.aux)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_u16() {
+        let status = Status::from_u16(0x6203);
+        assert_eq!(status, Status { state: State::Busy, reason: REASON_TIMEOUT, aux: 3 });
+        assert!(status.is_busy() && !status.has_error() && !status.has_warning());
+        assert!(status.aux_bit(0) && status.aux_bit(1) && !status.aux_bit(2));
+        assert_eq!(Status::from_u16(0xF000).state, State::Unknown(15));
+        assert_eq!(status.to_u16(), 0x6203);
+        assert_eq!(Status::from_u16(0xD1FF).to_u16(), 0xD1FF);
+    }
+
+    #[test]
+    fn decode_u32() {
+        let status = Status::from_u32(0x8C80_0001);
+        assert_eq!(status.state, State::Error);
+        assert!(status.has_reason(REASON_LOW_LIMIT) && status.has_reason(REASON_HIGH_LIMIT));
+        assert!(!status.has_reason(REASON_INHIBIT));
+        assert_eq!(status.aux, 0x80_0001);
+        assert!(status.aux_bit(23) && !status.aux_bit(24));
+        assert_eq!(Status::from_u32(0xD000_0000).state, State::DiagnosticError);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Status::from_u16(0x1000).to_string(), "IDLE");
+        assert_eq!(Status::from_u16(0x3903).to_string(), "WARN, inhibit, high limit, aux 0x3");
+        assert_eq!(Status::from_u16(0x9000).to_string(), "unknown state 9");
+    }
+}
@@ -0,0 +1,405 @@
+// *****************************************************************************
+// PILS PLC client library
+// Copyright (c) 2021 by the authors, see LICENSE
+//
+// This program is free software; you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation; either version 2 of the License, or (at your option) any later
+// version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more
+// details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program; if not, write to the Free Software Foundation, Inc.,
+// 59 Temple Place, Suite 330, Boston, MA  02111-1307  USA
+//
+// Module authors:
+//   Georg Brandl <g.brandl@fz-juelich.de>
+//
+// *****************************************************************************
+
+//! Helpers for testing code that uses zapf, without a PLC or simulator.
+//!
+//! `FakePlc` is a protocol backed by an in-memory image.  Devices can be
+//! placed in the image with `FakeDevice`, and then be driven and checked by
+//! name, e.g. setting a device's status and checking the targets written by
+//! the code under test.
+
+use crate::{Error, Result};
+use crate::proto::{Protocol, check_bit, check_length, check_mask};
+use crate::status::{State, Status};
+
+/// A device in the image of a `FakePlc`: its value, optionally followed by
+/// a target of the same size, and a 16-bit status word.
+#[derive(Debug, Clone)]
+pub struct FakeDevice {
+    name: String,
+    addr: usize,
+    size: usize,
+    value: Vec<u8>,
+    target: bool,
+    status: Option<Status>,
+}
+
+impl FakeDevice {
+    /// Create a device with a value of `size` bytes at `addr`.
+    pub fn new(name: &str, addr: usize, size: usize) -> Self {
+        Self { name: name.into(), addr, size, value: vec![0; size], target: false,
+               status: None }
+    }
+
+    /// Set the initial value, which must have the device's size.
+    pub fn with_value(mut self, value: &[u8]) -> Self {
+        assert_eq!(value.len(), self.size, "value of device {} has wrong size", self.name);
+        self.value = value.into();
+        self
+    }
+
+    /// Add a target after the value, initially equal to it.
+    pub fn with_target(mut self) -> Self {
+        self.target = true;
+        self
+    }
+
+    /// Add a status word in the given state after the value and target.
+    pub fn with_status(mut self, state: State) -> Self {
+        self.status = Some(Status { state, reason: 0, aux: 0 });
+        self
+    }
+
+    fn target_addr(&self) -> Option<usize> {
+        if self.target { Some(self.addr + self.size) } else { None }
+    }
+
+    fn status_addr(&self) -> Option<usize> {
+        self.status.map(|_| self.addr + self.size * (1 + self.target as usize))
+    }
+
+    fn end(&self) -> usize {
+        self.status_addr().map_or(self.addr + self.size * (1 + self.target as usize),
+                                  |addr| addr + 2)
+    }
+}
+
+/// A protocol backed by an in-memory image, recording all writes.
+pub struct FakePlc {
+    image: Vec<u8>,
+    devices: Vec<FakeDevice>,
+    writes: Vec<(usize, Vec<u8>)>,
+    calls: Vec<&'static str>,
+    failures: Vec<Error>,
+    connected: bool,
+}
+
+impl FakePlc {
+    /// Create an image of the given size, filled with zeros.
+    pub fn new(size: usize) -> Self {
+        Self { image: vec![0; size], devices: Vec::new(), writes: Vec::new(),
+               calls: Vec::new(), failures: Vec::new(), connected: false }
+    }
+
+    /// Create an image with the 2021.09 magic at the start.
+    pub fn with_magic(size: usize) -> Self {
+        let mut plc = Self::new(size.max(4));
+        plc.set(0, &2021.09f32.to_le_bytes());
+        plc
+    }
+
+    /// Place a device in the image, with its initial value and status.
+    ///
+    /// Panics if the device doesn't fit into the image, or overlaps another
+    /// device.
+    pub fn with_device(mut self, device: FakeDevice) -> Self {
+        assert!(device.end() <= self.image.len(), "device {} outside of the image", device.name);
+        if let Some(other) = self.devices.iter().find(
+            |other| device.addr < other.end() && other.addr < device.end())
+        {
+            panic!("device {} overlaps device {}", device.name, other.name);
+        }
+        self.set(device.addr, &device.value);
+        if let Some(addr) = device.target_addr() {
+            self.set(addr, &device.value);
+        }
+        if let (Some(addr), Some(status)) = (device.status_addr(), device.status) {
+            self.set(addr, &status.to_u16().to_le_bytes());
+        }
+        self.devices.push(device);
+        self
+    }
+
+    fn device(&self, name: &str) -> &FakeDevice {
+        self.devices.iter().find(|dev| dev.name == name)
+            .unwrap_or_else(|| panic!("no device named {}", name))
+    }
+
+    /// Set a device's value, as the PLC would when it changes.
+    pub fn set_value(&mut self, name: &str, value: &[u8]) {
+        let dev = self.device(name);
+        assert_eq!(value.len(), dev.size, "value of device {} has wrong size", name);
+        let addr = dev.addr;
+        self.set(addr, value);
+    }
+
+    pub fn value(&self, name: &str) -> &[u8] {
+        let dev = self.device(name);
+        self.get(dev.addr, dev.size)
+    }
+
+    /// Get a device's current target.  Panics if it has none.
+    pub fn target(&self, name: &str) -> &[u8] {
+        let dev = self.device(name);
+        let addr = dev.target_addr().unwrap_or_else(|| panic!("device {} has no target", name));
+        self.get(addr, dev.size)
+    }
+
+    /// Set a device's status word.  Panics if it has none.
+    pub fn set_status(&mut self, name: &str, status: Status) {
+        let addr = self.status_addr(name);
+        self.set(addr, &status.to_u16().to_le_bytes());
+    }
+
+    /// Get a device's status word.  Panics if it has none.
+    pub fn status(&self, name: &str) -> Status {
+        let addr = self.status_addr(name);
+        Status::from_u16(u16::from_le_bytes([self.image[addr], self.image[addr + 1]]))
+    }
+
+    fn status_addr(&self, name: &str) -> usize {
+        self.device(name).status_addr()
+            .unwrap_or_else(|| panic!("device {} has no status", name))
+    }
+
+    /// All targets written to a device so far, by writes covering its whole
+    /// target.  Panics if it has none.
+    pub fn target_writes(&self, name: &str) -> Vec<&[u8]> {
+        let dev = self.device(name);
+        let addr = dev.target_addr().unwrap_or_else(|| panic!("device {} has no target", name));
+        self.writes.iter()
+            .filter(|(start, data)| *start <= addr && addr + dev.size <= start + data.len())
+            .map(|(start, data)| &data[addr - start..addr - start + dev.size])
+            .collect()
+    }
+
+    /// Set image contents, without recording it as a write.
+    pub fn set(&mut self, addr: usize, data: &[u8]) {
+        self.image[addr..addr + data.len()].copy_from_slice(data);
+    }
+
+    /// Get image contents.
+    pub fn get(&self, addr: usize, len: usize) -> &[u8] {
+        &self.image[addr..addr + len]
+    }
+
+    /// All writes done via the protocol so far, as (address, data).
+    pub fn writes(&self) -> &[(usize, Vec<u8>)] {
+        &self.writes
+    }
+
+    /// Forget the recorded writes.
+    pub fn clear_writes(&mut self) {
+        self.writes.clear();
+    }
+
+    /// Names of the `Protocol` methods called so far, e.g. to check that a
+    /// wrapper forwards to the specialized methods.
+    pub fn calls(&self) -> &[&'static str] {
+        &self.calls
+    }
+
+    /// Forget the recorded calls.
+    pub fn clear_calls(&mut self) {
+        self.calls.clear();
+    }
+
+    /// Let the next read or write fail with the given error.  Several errors
+    /// are returned by the following operations in order.
+    pub fn fail_next(&mut self, error: Error) {
+        self.failures.insert(0, error);
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn check(&mut self, addr: usize, len: usize) -> Result<()> {
+        if let Some(error) = self.failures.pop() {
+            return Err(error);
+        }
+        check_length(addr, len, self.image.len())
+    }
+
+    fn read_image(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        self.check(addr, data.len())?;
+        data.copy_from_slice(&self.image[addr..addr + data.len()]);
+        Ok(())
+    }
+
+    fn write_image(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        self.check(addr, data.len())?;
+        self.store(addr, data);
+        Ok(())
+    }
+
+    fn store(&mut self, addr: usize, data: &[u8]) {
+        self.image[addr..addr + data.len()].copy_from_slice(data);
+        self.writes.push((addr, data.into()));
+    }
+}
+
+impl Protocol for FakePlc {
+    fn get_offsets(&self) -> &'static [usize] {
+        &[0]
+    }
+
+    fn set_offset(&mut self, _: usize) { }
+
+    fn connect(&mut self) -> Result<()> {
+        self.calls.push("connect");
+        self.connected = true;
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        self.calls.push("disconnect");
+        self.connected = false;
+    }
+
+    fn read_into(&mut self, addr: usize, data: &mut [u8]) -> Result<()> {
+        self.calls.push("read_into");
+        self.read_image(addr, data)
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        self.calls.push("write");
+        self.write_image(addr, data)
+    }
+
+    // the other methods are overridden as well, so that they are recorded as
+    // called, and don't show up as reads and writes
+
+    fn read_bit(&mut self, addr: usize, bit: u8) -> Result<bool> {
+        self.calls.push("read_bit");
+        check_bit(bit)?;
+        let mut byte = [0];
+        self.read_image(addr, &mut byte)?;
+        Ok(byte[0] & (1 << bit) != 0)
+    }
+
+    fn write_bit(&mut self, addr: usize, bit: u8, value: bool) -> Result<()> {
+        self.calls.push("write_bit");
+        check_bit(bit)?;
+        self.check(addr, 1)?;
+        let byte = self.image[addr] & !(1 << bit) | (value as u8) << bit;
+        self.store(addr, &[byte]);
+        Ok(())
+    }
+
+    fn write_masked(&mut self, addr: usize, data: &[u8], mask: &[u8]) -> Result<()> {
+        self.calls.push("write_masked");
+        check_mask(data, mask)?;
+        self.check(addr, data.len())?;
+        let merged = self.image[addr..addr + data.len()].iter().zip(data).zip(mask)
+            .map(|((cur, new), mask)| cur & !mask | new & mask)
+            .collect::<Vec<_>>();
+        self.store(addr, &merged);
+        Ok(())
+    }
+
+    fn read_ranges(&mut self, ranges: &[(usize, usize)]) -> Result<Vec<Vec<u8>>> {
+        self.calls.push("read_ranges");
+        ranges.iter().map(|&(addr, length)| {
+            let mut data = vec![0; length];
+            self.read_image(addr, &mut data).map(|_| data)
+        }).collect()
+    }
+
+    fn write_ranges(&mut self, writes: &[(usize, &[u8])]) -> Result<()> {
+        self.calls.push("write_ranges");
+        writes.iter().try_for_each(|&(addr, data)| self.write_image(addr, data))
+    }
+
+    fn write_read(&mut self, addr: usize, data: &[u8], read_addr: usize,
+                  result: &mut [u8]) -> Result<()> {
+        self.calls.push("write_read");
+        self.write_image(addr, data)?;
+        self.read_image(read_addr, result)
+    }
+}
+
+/// The `Protocol` methods that `call_overrides` calls, in order.
+#[cfg(test)]
+pub(crate) const OVERRIDES: &[&str] = &["read_bit", "write_bit", "write_masked", "read_ranges",
+                                        "write_ranges", "write_read"];
+
+/// Call each `Protocol` method with a default implementation, except
+/// `read_multi` which only uses `read_ranges`, to check that a wrapper
+/// forwards them to the overrides of its inner protocol.
+#[cfg(test)]
+pub(crate) fn call_overrides(proto: &mut dyn Protocol) {
+    proto.read_bit(0, 1).unwrap();
+    proto.write_bit(0, 1, true).unwrap();
+    proto.write_masked(1, &[1], &[1]).unwrap();
+    proto.read_ranges(&[(0, 1), (2, 1)]).unwrap();
+    proto.write_ranges(&[(0, &[1]), (2, &[1])]).unwrap();
+    proto.write_read(0, &[1], 2, &mut [0]).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plc() -> FakePlc {
+        FakePlc::with_magic(0x40)
+            .with_device(FakeDevice::new("sensor", 0x10, 2).with_value(&[1, 2])
+                         .with_status(State::Idle))
+            .with_device(FakeDevice::new("motor", 0x20, 4).with_target()
+                         .with_status(State::Busy))
+    }
+
+    #[test]
+    fn devices() {
+        let mut plc = plc();
+        assert_eq!(plc.get(0x10, 4), &[1, 2, 0x00, 0x10]);
+        assert_eq!(plc.status("motor").state, State::Busy);
+        plc.set_value("motor", &[1, 2, 3, 4]);
+        plc.set_status("sensor", Status { state: State::Warn, reason: 1, aux: 2 });
+        assert_eq!(plc.read(0x20, 4).unwrap(), [1, 2, 3, 4]);
+        assert_eq!(plc.read(0x12, 2).unwrap(), [0x02, 0x31]);
+        assert!(plc.writes().is_empty());
+    }
+
+    #[test]
+    fn target_writes() {
+        let mut plc = plc();
+        plc.write(0x24, &[5, 6, 7, 8]).unwrap();
+        plc.write(0x20, &[0; 12]).unwrap();
+        plc.write(0x24, &[9, 9]).unwrap();
+        assert_eq!(plc.target("motor"), &[9, 9, 0, 0]);
+        assert_eq!(plc.target_writes("motor"), [&[5, 6, 7, 8], &[0; 4]]);
+        // the whole-device write has reset the status as well
+        assert_eq!(plc.status("motor").state, State::Reset);
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps")]
+    fn overlapping_devices() {
+        plc().with_device(FakeDevice::new("other", 0x12, 2));
+    }
+
+    #[test]
+    fn calls() {
+        let mut plc = FakePlc::new(4);
+        plc.write_bit(0, 1, true).unwrap();
+        plc.write_masked(1, &[0xFF], &[0x0F]).unwrap();
+        assert!(plc.read_bit(0, 1).unwrap());
+        assert_eq!(plc.calls(), ["write_bit", "write_masked", "read_bit"]);
+        assert_eq!(plc.writes(), &[(0, vec![0x02]), (1, vec![0x0F])]);
+
+        plc.clear_calls();
+        call_overrides(&mut plc);
+        assert_eq!(plc.calls(), OVERRIDES);
+    }
+}